@@ -0,0 +1,3 @@
+-- Remember the format of the uploaded image, NULL for the images uploaded
+-- before this column existed (they are checked and fixed at startup)
+ALTER TABLE images ADD COLUMN format TEXT;
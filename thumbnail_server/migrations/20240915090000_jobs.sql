@@ -0,0 +1,18 @@
+-- Background work (thumbnails...) waiting to be done by the workers
+CREATE TABLE IF NOT EXISTS jobs (
+    id INTEGER PRIMARY KEY NOT NULL,
+    -- what to do, e.g. 'thumbnail'
+    kind TEXT NOT NULL,
+    image_id INTEGER NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+    -- 'pending', 'running', 'done' or 'failed'
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    -- unix timestamps, a pending job is not started before run_after
+    run_after INTEGER NOT NULL DEFAULT (unixepoch()),
+    created_at INTEGER NOT NULL DEFAULT (unixepoch()),
+    updated_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+CREATE INDEX IF NOT EXISTS jobs_pending ON jobs (status, run_after);
+CREATE INDEX IF NOT EXISTS jobs_image ON jobs (image_id);
@@ -0,0 +1,46 @@
+-- Tags get their own table, linked to the images by image_tags
+CREATE TABLE IF NOT EXISTS tags (
+    id INTEGER PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS image_tags (
+    image_id INTEGER NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+    tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+    PRIMARY KEY (image_id, tag_id)
+);
+
+CREATE INDEX IF NOT EXISTS image_tags_tag ON image_tags (tag_id);
+
+-- Split the existing free-form tag strings on commas and whitespace,
+-- the same way the server splits the tags of a new upload
+CREATE TEMP TABLE split_tags AS
+WITH RECURSIVE split(image_id, tag, rest) AS (
+    SELECT
+        id,
+        '',
+        lower(
+            replace(replace(replace(replace(tags, ',', ' '), char(9), ' '), char(10), ' '), char(13), ' ')
+        ) || ' '
+    FROM images
+    UNION ALL
+    SELECT
+        image_id,
+        substr(rest, 1, instr(rest, ' ') - 1),
+        substr(rest, instr(rest, ' ') + 1)
+    FROM split
+    WHERE rest <> ''
+)
+SELECT DISTINCT image_id, tag FROM split WHERE tag <> '';
+
+INSERT OR IGNORE INTO tags (name) SELECT DISTINCT tag FROM split_tags;
+
+INSERT OR IGNORE INTO image_tags (image_id, tag_id)
+SELECT split_tags.image_id, tags.id
+FROM split_tags
+JOIN tags ON tags.name = split_tags.tag;
+
+DROP TABLE split_tags;
+
+-- The tags now only live in image_tags
+ALTER TABLE images DROP COLUMN tags;
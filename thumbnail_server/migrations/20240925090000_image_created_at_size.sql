@@ -0,0 +1,11 @@
+-- When the image was uploaded (unix timestamp) and the size of the original in bytes.
+-- SQLite can't add a column defaulting to the current time, the server sets both on upload.
+-- The size of the existing images is filled at startup.
+ALTER TABLE images ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE images ADD COLUMN size INTEGER NOT NULL DEFAULT 0;
+
+UPDATE images SET created_at = unixepoch();
+
+-- /images pages through the images sorted by these columns
+CREATE INDEX IF NOT EXISTS images_created_at ON images (created_at, id);
+CREATE INDEX IF NOT EXISTS images_size ON images (size, id);
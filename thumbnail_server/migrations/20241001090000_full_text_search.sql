@@ -0,0 +1,57 @@
+-- A title and a description, searchable along with the tags
+ALTER TABLE images ADD COLUMN title TEXT NOT NULL DEFAULT '';
+ALTER TABLE images ADD COLUMN description TEXT NOT NULL DEFAULT '';
+
+-- Full-text index of the images, its rowid is the id of the image.
+-- The tags column holds the tags of the image separated by spaces.
+CREATE VIRTUAL TABLE IF NOT EXISTS images_fts USING fts5(
+    title,
+    description,
+    tags,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+INSERT INTO images_fts (rowid, title, description, tags)
+SELECT
+    images.id,
+    images.title,
+    images.description,
+    coalesce((
+        SELECT group_concat(tags.name, ' ') FROM image_tags
+        JOIN tags ON tags.id = image_tags.tag_id
+        WHERE image_tags.image_id = images.id
+    ), '')
+FROM images;
+
+-- Keep the index in sync with images and image_tags
+CREATE TRIGGER IF NOT EXISTS images_fts_insert AFTER INSERT ON images BEGIN
+    INSERT INTO images_fts (rowid, title, description, tags)
+    VALUES (new.id, new.title, new.description, '');
+END;
+
+CREATE TRIGGER IF NOT EXISTS images_fts_update AFTER UPDATE OF title, description ON images BEGIN
+    UPDATE images_fts SET title = new.title, description = new.description
+    WHERE rowid = new.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS images_fts_delete AFTER DELETE ON images BEGIN
+    DELETE FROM images_fts WHERE rowid = old.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS image_tags_fts_insert AFTER INSERT ON image_tags BEGIN
+    UPDATE images_fts SET tags = coalesce((
+        SELECT group_concat(tags.name, ' ') FROM image_tags
+        JOIN tags ON tags.id = image_tags.tag_id
+        WHERE image_tags.image_id = new.image_id
+    ), '')
+    WHERE rowid = new.image_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS image_tags_fts_delete AFTER DELETE ON image_tags BEGIN
+    UPDATE images_fts SET tags = coalesce((
+        SELECT group_concat(tags.name, ' ') FROM image_tags
+        JOIN tags ON tags.id = image_tags.tag_id
+        WHERE image_tags.image_id = old.image_id
+    ), '')
+    WHERE rowid = old.image_id;
+END;
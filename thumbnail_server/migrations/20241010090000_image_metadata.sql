@@ -0,0 +1,20 @@
+-- What is read from the file of an image at upload: dimensions, color type and EXIF.
+-- The metadata of the existing images is extracted by background jobs queued at startup.
+CREATE TABLE IF NOT EXISTS image_metadata (
+    image_id INTEGER PRIMARY KEY NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    -- e.g. 'rgb8', 'rgba8'
+    color_type TEXT NOT NULL,
+    camera TEXT,
+    -- unix timestamp
+    captured_at INTEGER,
+    -- EXIF orientation, 1 to 8
+    orientation INTEGER,
+    latitude REAL,
+    longitude REAL
+);
+
+-- /images and /api/search filter on the resolution
+CREATE INDEX IF NOT EXISTS image_metadata_width ON image_metadata (width);
+CREATE INDEX IF NOT EXISTS image_metadata_height ON image_metadata (height);
@@ -0,0 +1,4 @@
+-- Thumbnails now follow the EXIF orientation, make them again for the images
+-- that are stored rotated or mirrored. The jobs also drop their cached renditions.
+INSERT INTO jobs (kind, image_id)
+SELECT 'thumbnail', image_id FROM image_metadata WHERE orientation > 1;
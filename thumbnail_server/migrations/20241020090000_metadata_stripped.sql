@@ -0,0 +1,4 @@
+-- Privacy mode: 1 once the served copy of an image has had its metadata removed
+-- and its original moved to images/private. The existing images are stripped by
+-- background jobs queued at startup when the mode is turned on.
+ALTER TABLE images ADD COLUMN metadata_stripped INTEGER NOT NULL DEFAULT 0;
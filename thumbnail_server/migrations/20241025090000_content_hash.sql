@@ -0,0 +1,6 @@
+-- SHA-256 of the uploaded bytes, the original is stored under it.
+-- The same content is only stored once, an upload of known content returns the existing image.
+-- NULL until the server hashes the existing images at startup.
+ALTER TABLE images ADD COLUMN content_hash TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS images_content_hash ON images (content_hash);
@@ -0,0 +1,4 @@
+-- dHash of the image, compared bit by bit to find near-duplicates.
+-- The 64 bits are stored as a signed integer. NULL until the thumbnail job
+-- of the existing images runs again, they are queued at startup.
+ALTER TABLE images ADD COLUMN perceptual_hash INTEGER;
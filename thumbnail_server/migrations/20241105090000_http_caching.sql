@@ -0,0 +1,7 @@
+-- When the served file and the thumbnails of an image last changed, sent as
+-- Last-Modified. Their ETag is built from the content hash and these times.
+-- The thumbnail time stays NULL until the thumbnail job of a new image is done.
+ALTER TABLE images ADD COLUMN file_modified_at INTEGER;
+ALTER TABLE images ADD COLUMN thumbnails_at INTEGER;
+
+UPDATE images SET file_modified_at = created_at, thumbnails_at = created_at;
@@ -0,0 +1,4 @@
+-- Thumbnails now also come as WebP and AVIF for the clients accepting them,
+-- make them again for the existing images. Until their job runs the JPEG is sent.
+INSERT INTO jobs (kind, image_id)
+SELECT 'thumbnail', id FROM images;
@@ -0,0 +1,10 @@
+-- Images whose row was deleted but whose files may still be in the storage.
+-- The row of an image is deleted first, then its files: a file that could
+-- not be removed is tried again at startup, the entry goes once they are all gone.
+CREATE TABLE IF NOT EXISTS deleted_images (
+    id INTEGER PRIMARY KEY NOT NULL,
+    image_id INTEGER NOT NULL,
+    format TEXT,
+    content_hash TEXT,
+    deleted_at INTEGER NOT NULL
+);
@@ -0,0 +1,3 @@
+-- The comments and XMP of GIFs are now removed in privacy mode, the GIFs
+-- served as they were uploaded are stripped again at the next start.
+UPDATE images SET metadata_stripped = 0 WHERE metadata_stripped = 1 AND format = 'gif';
@@ -0,0 +1,4 @@
+-- WebPs are now stripped without being encoded again. The served copies made
+-- before were lossless re-encodings, they are made again from the originals
+-- kept in private/ at the next start.
+UPDATE images SET metadata_stripped = 0 WHERE metadata_stripped = 1 AND format = 'webp';
@@ -0,0 +1,81 @@
+-- Without AUTOINCREMENT, deleting the newest image gave its id to the next
+-- upload, and the clients and caches holding the old one got the new image.
+-- SQLite can only add it by building the table again. The foreign keys can't
+-- be turned off within the migration, dropping the old table deletes the
+-- tags, jobs and metadata of every image: they are put back afterwards.
+CREATE TEMP TABLE saved_image_tags AS SELECT * FROM image_tags;
+CREATE TEMP TABLE saved_jobs AS SELECT * FROM jobs;
+CREATE TEMP TABLE saved_image_metadata AS SELECT * FROM image_metadata;
+
+CREATE TABLE images_autoincrement (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    format TEXT,
+    created_at INTEGER NOT NULL DEFAULT 0,
+    size INTEGER NOT NULL DEFAULT 0,
+    title TEXT NOT NULL DEFAULT '',
+    description TEXT NOT NULL DEFAULT '',
+    metadata_stripped INTEGER NOT NULL DEFAULT 0,
+    content_hash TEXT,
+    perceptual_hash INTEGER,
+    file_modified_at INTEGER,
+    thumbnails_at INTEGER
+);
+
+INSERT INTO images_autoincrement (id, format, created_at, size, title, description,
+                                  metadata_stripped, content_hash, perceptual_hash,
+                                  file_modified_at, thumbnails_at)
+SELECT id, format, created_at, size, title, description,
+       metadata_stripped, content_hash, perceptual_hash,
+       file_modified_at, thumbnails_at
+FROM images;
+
+-- the indexes and triggers go with the old table
+DROP TABLE images;
+ALTER TABLE images_autoincrement RENAME TO images;
+
+CREATE INDEX images_created_at ON images (created_at, id);
+CREATE INDEX images_size ON images (size, id);
+CREATE UNIQUE INDEX images_content_hash ON images (content_hash);
+
+CREATE TRIGGER images_fts_insert AFTER INSERT ON images BEGIN
+    INSERT INTO images_fts (rowid, title, description, tags)
+    VALUES (new.id, new.title, new.description, '');
+END;
+
+CREATE TRIGGER images_fts_update AFTER UPDATE OF title, description ON images BEGIN
+    UPDATE images_fts SET title = new.title, description = new.description
+    WHERE rowid = new.id;
+END;
+
+CREATE TRIGGER images_fts_delete AFTER DELETE ON images BEGIN
+    DELETE FROM images_fts WHERE rowid = old.id;
+END;
+
+INSERT INTO image_tags SELECT * FROM saved_image_tags;
+INSERT INTO jobs SELECT * FROM saved_jobs;
+INSERT INTO image_metadata SELECT * FROM saved_image_metadata;
+DROP TABLE saved_image_tags;
+DROP TABLE saved_jobs;
+DROP TABLE saved_image_metadata;
+
+-- the search index went through the deletions too
+DELETE FROM images_fts;
+INSERT INTO images_fts (rowid, title, description, tags)
+SELECT
+    images.id,
+    images.title,
+    images.description,
+    coalesce((
+        SELECT group_concat(tags.name, ' ') FROM image_tags
+        JOIN tags ON tags.id = image_tags.tag_id
+        WHERE image_tags.image_id = images.id
+    ), '')
+FROM images;
+
+-- the ids of the images deleted before, whose files are still being removed,
+-- are not given again either
+INSERT INTO sqlite_sequence (name, seq)
+SELECT 'images', 0 WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = 'images');
+UPDATE sqlite_sequence
+SET seq = max(seq, (SELECT ifnull(max(image_id), 0) FROM deleted_images))
+WHERE name = 'images';
@@ -0,0 +1,8 @@
+-- The camera and the capture time of the stripped images were kept and given
+-- by /images, they are forgotten. The JPEGs that were not stored the right way
+-- up were encoded again, they are stripped again from the originals kept in
+-- private/ at the next start, now without losing quality.
+UPDATE image_metadata SET camera = NULL, captured_at = NULL
+WHERE image_id IN (SELECT id FROM images WHERE metadata_stripped = 1);
+UPDATE images SET metadata_stripped = 0
+WHERE metadata_stripped = 1 AND IFNULL(format, 'jpg') = 'jpg';
@@ -0,0 +1,64 @@
+//! Endpoints reserved to the admins, authenticated with
+//! `Authorization: Bearer <ADMIN_TOKEN>`
+
+use std::sync::Arc;
+
+use axum::{
+    extract::Path,
+    http::{header, HeaderMap},
+    response::Response,
+    Extension,
+};
+
+use crate::{
+    config::Config, error::AppError, files::image_file, ranges::requested_range, serve_file,
+    storage::SharedStorage,
+};
+
+/// Check the bearer token of a request. Without a configured token the
+/// admin endpoints don't exist.
+pub fn require_admin(headers: &HeaderMap, config: &Config) -> Result<(), AppError> {
+    let Some(expected) = &config.admin_token else {
+        return Err(AppError::NotFound("Not found".to_string()));
+    };
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| AppError::Unauthorized("An admin token is required".to_string()))?;
+
+    if !constant_time_eq(token.trim().as_bytes(), expected.as_bytes()) {
+        return Err(AppError::Unauthorized("Invalid admin token".to_string()));
+    }
+    Ok(())
+}
+
+/// compare two secrets without telling how many bytes match through the timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// the image exactly as it was uploaded, metadata included
+pub async fn get_original(
+    Path(id): Path<i64>,
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(storage): Extension<SharedStorage>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    require_admin(&headers, &config)?;
+    let file = image_file(&pool, id).await?;
+
+    // without a private copy, the served image is the original
+    let private = file.private_key();
+    let key = if storage.exists(&private).await? {
+        private
+    } else {
+        file.key()
+    };
+    let range = requested_range(&headers, None, None);
+    serve_file(&storage, &key, file.format.to_mime_type(), range).await
+}
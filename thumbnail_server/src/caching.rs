@@ -0,0 +1,214 @@
+//! HTTP caching of the images and thumbnails. Every response carries a strong
+//! ETag built from the content hash and a Last-Modified, so browsers and CDNs
+//! can revalidate with a cheap 304. A URL holding the current version
+//! (`?v=...`, see `image_url` and `thumbnail_url` of `/images`) never changes
+//! content, it is cached for a year without revalidation.
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::{config::Config, error::AppError, ranges, serve_file, storage::SharedStorage};
+
+/// how long a versioned URL is cached, one year as advised for `immutable`
+const IMMUTABLE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+/// query string of the cacheable endpoints
+#[derive(Deserialize)]
+pub struct VersionQuery {
+    /// the version the URL was built for
+    pub v: Option<String>,
+}
+
+/// Which bytes a file holds. Changes whenever the file does.
+#[derive(Debug, Clone)]
+pub struct Version {
+    /// opaque tag, the `v` of the versioned URLs
+    pub tag: String,
+    /// unix timestamp of the last change of the file
+    pub modified_at: i64,
+}
+
+/// The caching headers of a response
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    etag: String,
+    last_modified: i64,
+    /// the URL holds the current version, its content never changes
+    immutable: bool,
+}
+
+impl Version {
+    /// The policy of a response serving this version. `variant` tells apart
+    /// the files sharing a version (the thumbnail presets), `query` is the
+    /// version asked by the URL.
+    pub fn policy(&self, variant: Option<&str>, query: &VersionQuery) -> CachePolicy {
+        let etag = match variant {
+            Some(variant) => format!("\"{}-{variant}\"", self.tag),
+            None => format!("\"{}\"", self.tag),
+        };
+        CachePolicy {
+            etag,
+            last_modified: self.modified_at,
+            immutable: query.v.as_deref() == Some(self.tag.as_str()),
+        }
+    }
+}
+
+/// Version of the served original of an image, `None` until it is hashed.
+/// Keep in sync with `image_url` in `IMAGE_COLUMNS`.
+pub async fn image_version(pool: &SqlitePool, id: i64) -> Result<Option<Version>, AppError> {
+    version(
+        pool,
+        "SELECT content_hash || IIF(metadata_stripped, '-stripped', ''), file_modified_at
+         FROM images WHERE id = ?",
+        id,
+    )
+    .await
+}
+
+/// Version of the thumbnails of an image, `None` until they are made.
+/// Keep in sync with `thumbnail_url` in `IMAGE_COLUMNS`.
+pub async fn thumbnails_version(pool: &SqlitePool, id: i64) -> Result<Option<Version>, AppError> {
+    version(
+        pool,
+        "SELECT content_hash || '-' || thumbnails_at, thumbnails_at FROM images WHERE id = ?",
+        id,
+    )
+    .await
+}
+
+/// run a query giving a tag and a time, NULL when the version is unknown
+async fn version(pool: &SqlitePool, query: &str, id: i64) -> Result<Option<Version>, AppError> {
+    let row: Option<(Option<String>, Option<i64>)> =
+        sqlx::query_as(query).bind(id).fetch_optional(pool).await?;
+    let Some(row) = row else {
+        return Err(AppError::NotFound(format!("Image {id} not found")));
+    };
+    Ok(match row {
+        (Some(tag), Some(modified_at)) => Some(Version { tag, modified_at }),
+        _ => None,
+    })
+}
+
+/// Serve a file with its caching headers, or a 304 when the copy held by
+/// the client is still the current one. Without a policy the response can
+/// only be cached for `CACHE_MAX_AGE`.
+pub async fn serve_cached(
+    storage: &SharedStorage,
+    key: &str,
+    content_type: &str,
+    request: &HeaderMap,
+    policy: Option<CachePolicy>,
+    config: &Config,
+) -> Result<Response, AppError> {
+    let cache_control = match &policy {
+        Some(policy) if policy.immutable => {
+            format!("public, max-age={IMMUTABLE_MAX_AGE}, immutable")
+        }
+        _ => format!("public, max-age={}", config.cache_max_age),
+    };
+
+    let etag = policy.as_ref().map(|policy| policy.etag.as_str());
+    let last_modified = policy.as_ref().map(|policy| {
+        let modified = UNIX_EPOCH + Duration::from_secs(policy.last_modified.max(0) as u64);
+        httpdate::fmt_http_date(modified)
+    });
+
+    let mut response = match &policy {
+        Some(policy) if is_fresh(request, policy) => StatusCode::NOT_MODIFIED.into_response(),
+        _ => {
+            let range = ranges::requested_range(request, etag, last_modified.as_deref());
+            serve_file(storage, key, content_type, range).await?
+        }
+    };
+    // the error of a range outside the file is not the file, nothing to cache
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        return Ok(response);
+    }
+
+    let headers = response.headers_mut();
+    headers.insert(header::CACHE_CONTROL, cache_control.parse()?);
+    if let (Some(etag), Some(last_modified)) = (etag, last_modified) {
+        headers.insert(header::ETAG, etag.parse()?);
+        headers.insert(header::LAST_MODIFIED, last_modified.parse()?);
+    }
+    Ok(response)
+}
+
+/// Whether the copy of the client is the current one (RFC 9110 13.2.2):
+/// If-None-Match decides when it is sent, If-Modified-Since otherwise.
+fn is_fresh(request: &HeaderMap, policy: &CachePolicy) -> bool {
+    let if_none_match = request.get_all(header::IF_NONE_MATCH);
+    if if_none_match.iter().next().is_some() {
+        return if_none_match
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            // If-None-Match uses the weak comparison
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == policy.etag);
+    }
+
+    let Some(since) = request
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+    else {
+        return false;
+    };
+    // HTTP dates have a precision of one second
+    let since = since
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64);
+    policy.last_modified <= since
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    #[tokio::test]
+    async fn unsatisfiable_range_only_tells_the_size() {
+        let root = tempfile::tempdir().unwrap();
+        let storage: SharedStorage = Arc::new(LocalStorage::new(root.path().to_path_buf()));
+        storage.put("blobs/1.jpg", vec![0; 100]).await.unwrap();
+        let config = Config::from_env().unwrap();
+        let version = Version {
+            tag: "abc".to_string(),
+            modified_at: 1_700_000_000,
+        };
+        let policy = version.policy(None, &VersionQuery { v: None });
+
+        let mut request = HeaderMap::new();
+        request.insert(header::RANGE, "bytes=999999-".parse().unwrap());
+        let response = serve_cached(
+            &storage,
+            "blobs/1.jpg",
+            "image/jpeg",
+            &request,
+            Some(policy),
+            &config,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        let mut names: Vec<_> = response
+            .headers()
+            .keys()
+            .map(|name| name.as_str())
+            .collect();
+        names.sort_unstable();
+        assert_eq!(names, ["accept-ranges", "content-range"]);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */100");
+    }
+}
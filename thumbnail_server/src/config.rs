@@ -0,0 +1,318 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use image::{ImageFormat, Limits};
+
+/// Server configuration, read from the environment (and so from the .env file)
+#[derive(Debug)]
+pub struct Config {
+    /// every preset is generated for every image, the first one is the default
+    pub thumbnail_presets: Vec<ThumbnailPreset>,
+    /// formats of the thumbnails made besides the JPEG one (WebP, AVIF), best
+    /// first. They are sent to the clients that accept them.
+    pub thumbnail_formats: Vec<ImageFormat>,
+    /// largest width `/resize` will produce
+    pub resize_max_width: u32,
+    /// largest height `/resize` will produce
+    pub resize_max_height: u32,
+    /// widths and heights `/resize` accepts, empty means any size up to the maximum.
+    /// Every accepted size ends up cached on disk, so keep the list short.
+    pub resize_allowed_sizes: Vec<u32>,
+    /// renditions `/resize` makes at the same time, each one decodes a whole original
+    pub resize_concurrency: usize,
+    /// formats accepted by `/upload`
+    pub upload_allowed_formats: Vec<ImageFormat>,
+    /// largest width accepted by `/upload`
+    pub upload_max_width: u32,
+    /// largest height accepted by `/upload`
+    pub upload_max_height: u32,
+    /// largest image accepted by `/upload`, in bytes
+    pub upload_max_bytes: usize,
+    /// most images accepted in one `/upload` request
+    pub upload_max_files: usize,
+    /// number of images of one `/upload` request stored at the same time
+    pub upload_concurrency: usize,
+    /// largest archive accepted by `/import/zip`, in bytes
+    pub import_max_bytes: u64,
+    /// memory the images decoded at the same time may take together (uploads,
+    /// thumbnails, `/resize`), in bytes. A decode waits for its share.
+    pub decode_max_memory: u64,
+    /// number of background workers running jobs (thumbnails...)
+    pub job_workers: usize,
+    /// a job is marked as failed after this many attempts
+    pub job_max_attempts: i64,
+    /// Privacy mode: the served images have their EXIF, XMP and GPS metadata removed,
+    /// the untouched originals are kept aside for the admins
+    pub strip_metadata: bool,
+    /// bearer token of the `/admin` endpoints, they are disabled without it
+    pub admin_token: Option<String>,
+    /// two images whose perceptual hashes differ by at most this many bits
+    /// are reported as near-duplicates
+    pub similar_max_distance: u32,
+    /// where the images and their thumbnails are stored
+    pub storage: StorageConfig,
+    /// how long (in seconds) clients may keep an image or a thumbnail fetched
+    /// from a URL without version before checking it again
+    pub cache_max_age: u64,
+}
+
+/// Which storage backend holds the files, see the `storage` module
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    /// files under a folder of this machine
+    Local { root: PathBuf },
+    /// objects in a bucket of S3 or of a compatible server such as MinIO,
+    /// so several servers can share the same images
+    S3(S3Config),
+}
+
+/// Where the S3 bucket is and how to log into it
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    /// URL of an S3 compatible server, AWS when missing
+    pub endpoint: Option<String>,
+    /// the usual AWS variables and profile are used when the keys are missing
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    /// address the bucket as `endpoint/bucket` rather than `bucket.endpoint`,
+    /// what MinIO expects
+    pub path_style: bool,
+}
+
+/// A named thumbnail size, e.g. "small=100x100"
+#[derive(Debug, Clone)]
+pub struct ThumbnailPreset {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+const DEFAULT_THUMBNAIL_PRESETS: &str = "small=100x100,medium=320x320,large=1024x1024";
+const DEFAULT_THUMBNAIL_FORMATS: &str = "avif,webp";
+const DEFAULT_RESIZE_MAX_SIZE: u32 = 2048;
+const DEFAULT_UPLOAD_ALLOWED_FORMATS: &str = "jpg,png,gif,webp,tiff,bmp";
+const DEFAULT_UPLOAD_MAX_SIZE: u32 = 12000;
+const DEFAULT_UPLOAD_MAX_BYTES: usize = 50 * 1024 * 1024;
+const DEFAULT_UPLOAD_MAX_FILES: usize = 500;
+const DEFAULT_IMPORT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_DECODE_MAX_MEMORY: u64 = 2 * 1024 * 1024 * 1024;
+const DEFAULT_JOB_MAX_ATTEMPTS: i64 = 5;
+const DEFAULT_SIMILAR_MAX_DISTANCE: u32 = 10;
+const DEFAULT_CACHE_MAX_AGE: u64 = 300;
+const DEFAULT_STORAGE_ROOT: &str = "images";
+const DEFAULT_S3_REGION: &str = "us-east-1";
+const DEFAULT_RESIZE_ALLOWED_SIZES: &str =
+    "64,100,128,160,200,256,320,400,480,640,768,800,960,1024,1280,1600,1920,2048";
+
+impl Config {
+    /// build the configuration from the environment variables,
+    /// using the defaults for the ones that are not set
+    pub fn from_env() -> anyhow::Result<Self> {
+        let presets = std::env::var("THUMBNAIL_PRESETS")
+            .unwrap_or_else(|_| DEFAULT_THUMBNAIL_PRESETS.to_string());
+        let allowed_sizes = std::env::var("RESIZE_ALLOWED_SIZES")
+            .unwrap_or_else(|_| DEFAULT_RESIZE_ALLOWED_SIZES.to_string());
+        let thumbnail_formats = std::env::var("THUMBNAIL_FORMATS")
+            .unwrap_or_else(|_| DEFAULT_THUMBNAIL_FORMATS.to_string());
+        let allowed_formats = std::env::var("UPLOAD_ALLOWED_FORMATS")
+            .unwrap_or_else(|_| DEFAULT_UPLOAD_ALLOWED_FORMATS.to_string());
+
+        Ok(Config {
+            thumbnail_presets: parse_presets(&presets).context("invalid THUMBNAIL_PRESETS")?,
+            thumbnail_formats: parse_thumbnail_formats(&thumbnail_formats)
+                .context("invalid THUMBNAIL_FORMATS")?,
+            resize_max_width: env_or("RESIZE_MAX_WIDTH", DEFAULT_RESIZE_MAX_SIZE)?,
+            resize_max_height: env_or("RESIZE_MAX_HEIGHT", DEFAULT_RESIZE_MAX_SIZE)?,
+            resize_allowed_sizes: parse_list(&allowed_sizes)
+                .context("invalid RESIZE_ALLOWED_SIZES")?,
+            // decoding and encoding are CPU bound
+            resize_concurrency: env_or("RESIZE_CONCURRENCY", available_cpus())?.max(1),
+            upload_allowed_formats: parse_formats(&allowed_formats)
+                .context("invalid UPLOAD_ALLOWED_FORMATS")?,
+            upload_max_width: env_or("UPLOAD_MAX_WIDTH", DEFAULT_UPLOAD_MAX_SIZE)?,
+            upload_max_height: env_or("UPLOAD_MAX_HEIGHT", DEFAULT_UPLOAD_MAX_SIZE)?,
+            upload_max_bytes: env_or("UPLOAD_MAX_BYTES", DEFAULT_UPLOAD_MAX_BYTES)?,
+            upload_max_files: env_or("UPLOAD_MAX_FILES", DEFAULT_UPLOAD_MAX_FILES)?.max(1),
+            // validating and hashing are CPU bound too
+            upload_concurrency: env_or("UPLOAD_CONCURRENCY", available_cpus())?.max(1),
+            import_max_bytes: env_or("IMPORT_MAX_BYTES", DEFAULT_IMPORT_MAX_BYTES)?,
+            decode_max_memory: env_or("DECODE_MAX_MEMORY", DEFAULT_DECODE_MAX_MEMORY)?,
+            // thumbnails are CPU bound, one worker per CPU by default
+            job_workers: env_or("JOB_WORKERS", available_cpus())?.max(1),
+            job_max_attempts: env_or("JOB_MAX_ATTEMPTS", DEFAULT_JOB_MAX_ATTEMPTS)?.max(1),
+            strip_metadata: env_or("STRIP_METADATA", false)?,
+            admin_token: env_text("ADMIN_TOKEN"),
+            similar_max_distance: env_or("SIMILAR_MAX_DISTANCE", DEFAULT_SIMILAR_MAX_DISTANCE)?
+                .min(64),
+            storage: storage_from_env()?,
+            cache_max_age: env_or("CACHE_MAX_AGE", DEFAULT_CACHE_MAX_AGE)?,
+        })
+    }
+
+    /// find a preset by its name
+    pub fn preset(&self, name: &str) -> Option<&ThumbnailPreset> {
+        self.thumbnail_presets.iter().find(|p| p.name == name)
+    }
+
+    /// the preset served by `/thumb/:id`
+    pub fn default_preset(&self) -> &ThumbnailPreset {
+        &self.thumbnail_presets[0]
+    }
+
+    /// Limits of the decoder for the uploads: enough memory for the largest
+    /// accepted image in RGBA with 8 bits per channel, and a quarter more for the
+    /// buffers of the decoder. The dimensions are checked by the validation,
+    /// with a clearer error.
+    pub fn decoder_limits(&self) -> Limits {
+        let pixels = u64::from(self.upload_max_width) * u64::from(self.upload_max_height);
+        let mut limits = Limits::no_limits();
+        limits.max_alloc = Some(pixels * 4 + pixels);
+        limits
+    }
+}
+
+/// read the storage backend and its settings
+fn storage_from_env() -> anyhow::Result<StorageConfig> {
+    let backend = env_text("STORAGE_BACKEND").unwrap_or_else(|| "local".to_string());
+    match backend.as_str() {
+        "local" => Ok(StorageConfig::Local {
+            root: env_text("STORAGE_ROOT")
+                .unwrap_or_else(|| DEFAULT_STORAGE_ROOT.to_string())
+                .into(),
+        }),
+        "s3" => {
+            let endpoint = env_text("S3_ENDPOINT");
+            Ok(StorageConfig::S3(S3Config {
+                bucket: env_text("S3_BUCKET").context("S3_BUCKET is required with s3 storage")?,
+                region: env_text("S3_REGION").unwrap_or_else(|| DEFAULT_S3_REGION.to_string()),
+                access_key: env_text("S3_ACCESS_KEY"),
+                secret_key: env_text("S3_SECRET_KEY"),
+                // compatible servers rarely have a DNS entry per bucket
+                path_style: env_or("S3_PATH_STYLE", endpoint.is_some())?,
+                endpoint,
+            }))
+        }
+        _ => anyhow::bail!("invalid STORAGE_BACKEND: {backend:?}, expected local or s3"),
+    }
+}
+
+/// a text environment variable, trimmed, `None` when it is not set or empty
+fn env_text(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// parse a list of presets such as "small=100x100,medium=320x320"
+fn parse_presets(text: &str) -> anyhow::Result<Vec<ThumbnailPreset>> {
+    let mut presets: Vec<ThumbnailPreset> = Vec::new();
+    for entry in text.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, size) = entry
+            .split_once('=')
+            .with_context(|| format!("expected name=WIDTHxHEIGHT, got {entry:?}"))?;
+        let name = name.trim();
+        let (width, height) = parse_size(size.trim())?;
+
+        // the name ends up in file names, keep it simple
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            anyhow::bail!("invalid preset name {name:?}");
+        }
+        if presets.iter().any(|p| p.name == name) {
+            anyhow::bail!("duplicate preset name {name:?}");
+        }
+
+        presets.push(ThumbnailPreset {
+            name: name.to_string(),
+            width,
+            height,
+        });
+    }
+
+    if presets.is_empty() {
+        anyhow::bail!("at least one thumbnail preset is required");
+    }
+    Ok(presets)
+}
+
+/// parse a size such as "100x100"
+fn parse_size(text: &str) -> anyhow::Result<(u32, u32)> {
+    let (width, height) = text
+        .split_once('x')
+        .with_context(|| format!("expected WIDTHxHEIGHT, got {text:?}"))?;
+    let width: u32 = width
+        .parse()
+        .with_context(|| format!("invalid width in {text:?}"))?;
+    let height: u32 = height
+        .parse()
+        .with_context(|| format!("invalid height in {text:?}"))?;
+    if width == 0 || height == 0 {
+        anyhow::bail!("size must not be zero: {text:?}");
+    }
+    Ok((width, height))
+}
+
+/// number of CPUs this process can use
+fn available_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// read and parse an environment variable, or use the default when it is not set
+fn env_or<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid {name}: {value:?}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// parse a comma separated list such as "100,200,300"
+fn parse_list<T>(text: &str) -> anyhow::Result<Vec<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    text.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse()
+                .with_context(|| format!("invalid value {item:?}"))
+        })
+        .collect()
+}
+
+/// parse the formats of the thumbnails besides JPEG, such as "avif,webp"
+fn parse_thumbnail_formats(text: &str) -> anyhow::Result<Vec<ImageFormat>> {
+    let formats = parse_formats(text)?;
+    if let Some(format) = formats
+        .iter()
+        .find(|format| !matches!(format, ImageFormat::WebP | ImageFormat::Avif))
+    {
+        anyhow::bail!("{format:?} thumbnails are not supported, only webp and avif");
+    }
+    Ok(formats)
+}
+
+/// parse a list of image formats given by their extension, such as "jpg,png"
+fn parse_formats(text: &str) -> anyhow::Result<Vec<ImageFormat>> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            ImageFormat::from_extension(item).with_context(|| format!("unknown format {item:?}"))
+        })
+        .collect()
+}
@@ -0,0 +1,289 @@
+//! Deleting an image. Its row goes first, in one transaction: the tags, metadata,
+//! jobs and search entry follow through `ON DELETE CASCADE` and the triggers, and
+//! an entry of `deleted_images` remembers which files belonged to it. The files
+//! (original, private copy, thumbnails, cached renditions) are removed afterwards:
+//! when that fails the image is already gone for the clients, and the removal
+//! is tried again at the next start.
+
+use std::sync::Arc;
+
+use axum::{extract::Path, http::StatusCode, response::Redirect, Extension};
+use sqlx::{prelude::FromRow, SqlitePool};
+
+use crate::{
+    config::{Config, ThumbnailPreset},
+    error::{AppError, HtmlError},
+    files::{content_in_use, lock_content, stored_format, ImageFile},
+    resize,
+    similar::SimilarIndex,
+    storage::{SharedStorage, Storage},
+    thumbnail_key, THUMBNAIL_FORMATS,
+};
+
+/// A row of `deleted_images`, an image whose files may still be stored
+#[derive(FromRow, Debug)]
+struct DeletedImage {
+    id: i64,
+    image_id: i64,
+    format: Option<String>,
+    content_hash: Option<String>,
+}
+
+/// `DELETE /image/:id`, answers a 204 once the image is gone
+pub async fn delete_image(
+    Path(id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(index): Extension<SimilarIndex>,
+) -> Result<StatusCode, AppError> {
+    remove_image(&pool, &config, storage.as_ref(), &index, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /image/:id/delete`, the same for an HTML form, back to the thumbnails
+pub async fn delete_image_form(
+    Path(id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(index): Extension<SimilarIndex>,
+) -> Result<Redirect, HtmlError> {
+    remove_image(&pool, &config, storage.as_ref(), &index, id).await?;
+    Ok(Redirect::to("/"))
+}
+
+/// delete the row of an image, then its files
+async fn remove_image(
+    pool: &SqlitePool,
+    config: &Config,
+    storage: &dyn Storage,
+    index: &SimilarIndex,
+    id: i64,
+) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+    let deleted = sqlx::query_as::<_, DeletedImage>(
+        "INSERT INTO deleted_images (image_id, format, content_hash, deleted_at)
+         SELECT id, format, content_hash, unixepoch() FROM images WHERE id = ?
+         RETURNING id, image_id, format, content_hash",
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Image {id} not found")))?;
+    sqlx::query("DELETE FROM images WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    index.remove(id);
+
+    if let Err(e) = remove_files(pool, config, storage, &deleted).await {
+        eprintln!(
+            "failed to remove the files of image {id}, trying again at the next start: {e:#}"
+        );
+    }
+    Ok(())
+}
+
+/// remove the files of the images deleted while their storage was unavailable
+pub async fn remove_deleted_files(
+    pool: &SqlitePool,
+    config: &Config,
+    storage: &dyn Storage,
+) -> anyhow::Result<()> {
+    let deleted = sqlx::query_as::<_, DeletedImage>(
+        "SELECT id, image_id, format, content_hash FROM deleted_images",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for deleted in deleted {
+        if let Err(e) = remove_files(pool, config, storage, &deleted).await {
+            eprintln!(
+                "failed to remove the files of image {}: {e:#}",
+                deleted.image_id
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Remove the files of a deleted image, then forget it: when the storage fails
+/// it is still there for the next start to try again. A new upload can take
+/// the same content meanwhile, its files are then left alone. The content is
+/// locked until the end, so no upload can store them again between the check
+/// and the removal. The database is only read meanwhile, not locked.
+async fn remove_files(
+    pool: &SqlitePool,
+    config: &Config,
+    storage: &dyn Storage,
+    deleted: &DeletedImage,
+) -> anyhow::Result<()> {
+    // an upload of the same content writes its files before inserting its row
+    let _content = match &deleted.content_hash {
+        Some(hash) => Some(lock_content(hash).await),
+        None => None,
+    };
+
+    let id = deleted.image_id;
+    let id_in_use: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM images WHERE id = ?)")
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+    let content_in_use = match &deleted.content_hash {
+        Some(hash) => content_in_use(pool, hash).await?,
+        // stored under its id
+        None => id_in_use,
+    };
+
+    if !content_in_use {
+        let file = ImageFile {
+            id,
+            format: stored_format(deleted.format.as_deref()),
+            content_hash: deleted.content_hash.clone(),
+        };
+        storage.delete(&file.key()).await?;
+        storage.delete(&file.private_key()).await?;
+    }
+    if !id_in_use {
+        remove_thumbnails(storage, &config.thumbnail_presets, id).await?;
+        resize::remove_cached_renditions(storage, id).await?;
+    }
+
+    sqlx::query("DELETE FROM deleted_images WHERE id = ?")
+        .bind(deleted.id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Remove the thumbnails of an image, in every format including the ones no
+/// longer configured. Their keys are known, listing them would read the
+/// whole folder. The thumbnails of presets no longer configured stay.
+pub async fn remove_thumbnails(
+    storage: &dyn Storage,
+    presets: &[ThumbnailPreset],
+    id: i64,
+) -> anyhow::Result<()> {
+    for preset in presets {
+        for format in THUMBNAIL_FORMATS {
+            storage.delete(&thumbnail_key(id, preset, format)).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::migrate::Migrate;
+
+    use crate::test_util::empty_pool;
+
+    /// the migration building `images` again with AUTOINCREMENT
+    const AUTOINCREMENT_MIGRATION: i64 = 20241130090000;
+
+    #[tokio::test]
+    async fn autoincrement_migration_keeps_the_images() {
+        let pool = empty_pool().await;
+        let migrator = sqlx::migrate!("./migrations");
+        let mut conn = pool.acquire().await.unwrap();
+        conn.ensure_migrations_table().await.unwrap();
+        for migration in migrator.iter() {
+            if migration.version < AUTOINCREMENT_MIGRATION {
+                conn.apply(migration).await.unwrap();
+            }
+        }
+        drop(conn);
+
+        // image 7 was the newest, its files are still being removed
+        sqlx::raw_sql(
+            "INSERT INTO images (id, title, description, content_hash) VALUES
+                 (1, 'harbour', 'boats at dawn', 'aa'),
+                 (2, 'garden', '', 'bb'),
+                 (5, '', 'snowy peak', 'cc');
+             INSERT INTO tags (id, name) VALUES (1, 'sea'), (2, 'flowers');
+             INSERT INTO image_tags (image_id, tag_id) VALUES (1, 1), (2, 2), (5, 1);
+             INSERT INTO jobs (kind, image_id, status) VALUES
+                 ('thumbnail', 1, 'done'), ('thumbnail', 5, 'pending'),
+                 ('metadata', 5, 'failed');
+             INSERT INTO image_metadata (image_id, width, height, color_type, camera)
+             VALUES (1, 640, 480, 'rgb8', 'Pentax'), (5, 20, 10, 'rgba8', NULL);
+             INSERT INTO deleted_images (image_id, format, content_hash, deleted_at)
+             VALUES (7, 'png', 'dd', 0);",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        migrator.run(&pool).await.unwrap();
+
+        let tags: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT image_id, name FROM image_tags JOIN tags ON tags.id = tag_id
+             ORDER BY image_id",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            tags,
+            [
+                (1, "sea".to_string()),
+                (2, "flowers".to_string()),
+                (5, "sea".to_string())
+            ]
+        );
+        let jobs: Vec<(String, i64, String)> =
+            sqlx::query_as("SELECT kind, image_id, status FROM jobs ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            jobs,
+            [
+                ("thumbnail".to_string(), 1, "done".to_string()),
+                ("thumbnail".to_string(), 5, "pending".to_string()),
+                ("metadata".to_string(), 5, "failed".to_string())
+            ]
+        );
+        let metadata: Vec<(i64, i64, Option<String>)> =
+            sqlx::query_as("SELECT image_id, width, camera FROM image_metadata ORDER BY image_id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            metadata,
+            [(1, 640, Some("Pentax".to_string())), (5, 20, None)]
+        );
+
+        let search = |text: &'static str| {
+            let pool = pool.clone();
+            async move {
+                sqlx::query_scalar::<_, i64>(
+                    "SELECT rowid FROM images_fts WHERE images_fts MATCH ? ORDER BY rowid",
+                )
+                .bind(text)
+                .fetch_all(&pool)
+                .await
+                .unwrap()
+            }
+        };
+        assert_eq!(search("tags:sea").await, [1, 5]);
+        assert_eq!(search("boats").await, [1]);
+        assert_eq!(search("garden").await, [2]);
+        assert_eq!(search("snowy").await, [5]);
+
+        // neither the newest image nor the deleted one gives its id again
+        let insert = "INSERT INTO images (title) VALUES ('new') RETURNING id";
+        let id: i64 = sqlx::query_scalar(insert).fetch_one(&pool).await.unwrap();
+        assert_eq!(id, 8);
+        sqlx::query("DELETE FROM images WHERE id = 8")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let id: i64 = sqlx::query_scalar(insert).fetch_one(&pool).await.unwrap();
+        assert_eq!(id, 9);
+        // the triggers were made again
+        assert_eq!(search("new").await, [9]);
+    }
+}
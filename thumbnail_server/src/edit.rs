@@ -0,0 +1,85 @@
+//! Editing the details of an uploaded image (tags, title, description)
+//! without uploading it again.
+
+use axum::{
+    extract::{rejection::JsonRejection, Path},
+    Extension, Json,
+};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    error::AppError,
+    tags::{self, parse_tags},
+    ImageRecord, IMAGE_COLUMNS,
+};
+
+/// Body of `PATCH /image/:id`, every field is optional.
+/// The tags are split and normalized like the ones of an upload.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ImagePatch {
+    /// replace every tag of the image
+    tags: Option<Vec<String>>,
+    /// tags to attach to the image
+    add_tags: Option<Vec<String>>,
+    /// tags to detach from the image
+    remove_tags: Option<Vec<String>>,
+    title: Option<String>,
+    description: Option<String>,
+}
+
+/// change the tags, title or description of an image and return it
+pub async fn update_image(
+    Path(id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+    patch: Result<Json<ImagePatch>, JsonRejection>,
+) -> Result<Json<ImageRecord>, AppError> {
+    let Json(patch) = patch.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    if patch.tags.is_some() && (patch.add_tags.is_some() || patch.remove_tags.is_some()) {
+        return Err(AppError::BadRequest(
+            "tags replaces every tag, it can't be used with add_tags or remove_tags".to_string(),
+        ));
+    }
+    let normalize = |tags: &[String]| parse_tags(&tags.join(","));
+
+    let mut tx = pool.begin().await?;
+    // also tells whether the image exists, the title and description
+    // are kept when they are not given
+    let updated = sqlx::query(
+        "UPDATE images SET title = IFNULL(?, title), description = IFNULL(?, description)
+         WHERE id = ?",
+    )
+    .bind(patch.title.as_deref().map(str::trim))
+    .bind(patch.description.as_deref().map(str::trim))
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::NotFound(format!("Image {id} not found")));
+    }
+
+    if let Some(replacement) = &patch.tags {
+        sqlx::query("DELETE FROM image_tags WHERE image_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tags::add_image_tags(&mut tx, id, &normalize(replacement)).await?;
+    }
+    if let Some(added) = &patch.add_tags {
+        tags::add_image_tags(&mut tx, id, &normalize(added)).await?;
+    }
+    if let Some(removed) = &patch.remove_tags {
+        tags::remove_image_tags(&mut tx, id, &normalize(removed)).await?;
+    }
+    tx.commit().await?;
+
+    let image = sqlx::query_as::<_, ImageRecord>(&format!(
+        "SELECT {IMAGE_COLUMNS}, NULL AS snippet FROM images WHERE images.id = ?"
+    ))
+    .bind(id)
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Image {id} not found")))?;
+    Ok(Json(image))
+}
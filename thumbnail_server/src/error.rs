@@ -0,0 +1,177 @@
+use axum::{
+    extract::multipart::MultipartError,
+    http::{header, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+/// Everything that can go wrong inside a handler.
+/// Each variant maps to one HTTP status code, so clients can tell
+/// "image 42 doesn't exist" apart from "the server crashed".
+#[derive(Debug)]
+pub enum AppError {
+    /// the requested image (or one of its files) does not exist
+    NotFound(String),
+    /// the request itself is wrong: missing or unknown field, invalid text...
+    BadRequest(String),
+    /// the endpoint needs credentials that are missing or wrong
+    Unauthorized(String),
+    /// the request body is larger than we accept
+    PayloadTooLarge(String),
+    /// the uploaded payload is not an image we can handle
+    UnsupportedMedia(String),
+    /// the upload looks like an image but is broken or too large
+    Unprocessable(String),
+    /// anything else (database, filesystem, image decoding...)
+    Internal(anyhow::Error),
+}
+
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMedia(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// short machine readable name of the error, used in JSON bodies
+    fn kind(&self) -> &'static str {
+        match self {
+            AppError::NotFound(_) => "not_found",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::UnsupportedMedia(_) => "unsupported_media_type",
+            AppError::Unprocessable(_) => "unprocessable_entity",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    /// a broken multipart body is the client's fault, unless it is too large
+    pub fn from_multipart(error: MultipartError) -> Self {
+        if error.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            AppError::PayloadTooLarge(error.body_text())
+        } else {
+            AppError::BadRequest(format!("Invalid multipart body: {}", error.body_text()))
+        }
+    }
+
+    /// message shown to the client.
+    /// Internal errors are logged but never leaked to the client.
+    pub fn message(&self) -> String {
+        match self {
+            AppError::NotFound(message)
+            | AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::PayloadTooLarge(message)
+            | AppError::UnsupportedMedia(message)
+            | AppError::Unprocessable(message) => message.clone(),
+            AppError::Internal(error) => {
+                eprintln!("internal error: {error:?}");
+                "internal server error".to_string()
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::NotFound(message)
+            | AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::PayloadTooLarge(message)
+            | AppError::UnsupportedMedia(message)
+            | AppError::Unprocessable(message) => write!(f, "{message}"),
+            AppError::Internal(error) => write!(f, "{error:#}"),
+        }
+    }
+}
+
+// Any error that anyhow understands (sqlx, io, image...) becomes an internal error,
+// this is what allows to use `?` inside the handlers
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(error: E) -> Self {
+        AppError::Internal(error.into())
+    }
+}
+
+/// JSON body of an error
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    error: &'static str,
+    message: String,
+}
+
+impl AppError {
+    /// the error as sent to API clients, also used for each file of a bulk upload
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            error: self.kind(),
+            message: self.message(),
+        }
+    }
+}
+
+/// API clients get a JSON body: {"error": "not_found", "message": "..."}
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(self.body())).into_response();
+        // tell the client how to authenticate
+        if let AppError::Unauthorized(_) = self {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+/// Same as `AppError` but rendered as an HTML page,
+/// used by the handlers that answer a browser form.
+#[derive(Debug)]
+pub struct HtmlError(pub AppError);
+
+impl<E> From<E> for HtmlError
+where
+    E: Into<AppError>,
+{
+    fn from(error: E) -> Self {
+        HtmlError(error.into())
+    }
+}
+
+impl IntoResponse for HtmlError {
+    fn into_response(self) -> Response {
+        let status = self.0.status();
+        let content = include_str!("error.html")
+            .replace("{status}", status.as_str())
+            .replace("{message}", &escape_html(&self.0.message()));
+        (status, Html(content)).into_response()
+    }
+}
+
+/// escape the characters that have a meaning in HTML
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,185 @@
+//! Where the original of an image is stored. Originals are content addressed:
+//! their file is named after the SHA-256 of the upload, so the same bytes are
+//! never stored twice. Images stored before that keep their `{id}.{ext}`
+//! file until their hash is computed at startup.
+
+use std::fmt::Write;
+
+use image::ImageFormat;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Row, Sqlite};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{error::AppError, format_name, original_key, privacy::PRIVATE_DIR, storage::Storage};
+
+/// folder of the content addressed originals
+const BLOB_DIR: &str = "blobs";
+
+/// locks of `lock_content`, picked by the first byte of the hash
+static CONTENT_LOCKS: [Mutex<()>; 256] = [const { Mutex::const_new(()) }; 256];
+
+/// images uploaded before the format was recorded were all saved as .jpg
+pub const LEGACY_FORMAT: ImageFormat = ImageFormat::Jpeg;
+
+/// format of a stored image from the `format` column of its row
+pub fn stored_format(format: Option<&str>) -> ImageFormat {
+    format
+        .and_then(ImageFormat::from_extension)
+        .unwrap_or(LEGACY_FORMAT)
+}
+
+/// The stored original of an image
+#[derive(Debug, Clone)]
+pub struct ImageFile {
+    pub id: i64,
+    pub format: ImageFormat,
+    /// SHA-256 of the upload in hexadecimal, `None` for an image whose
+    /// hash was never computed
+    pub content_hash: Option<String>,
+}
+
+impl ImageFile {
+    /// storage key of the served original
+    pub fn key(&self) -> String {
+        match &self.content_hash {
+            Some(hash) => blob_key(hash, self.format),
+            None => original_key(self.id, self.format),
+        }
+    }
+
+    /// storage key of the untouched original in privacy mode, see the `privacy` module
+    pub fn private_key(&self) -> String {
+        match &self.content_hash {
+            Some(hash) => private_blob_key(hash, self.format),
+            None => format!("{PRIVATE_DIR}/{}.{}", self.id, format_name(self.format)),
+        }
+    }
+}
+
+/// find where the original of an image is stored
+pub async fn image_file(pool: &sqlx::SqlitePool, id: i64) -> Result<ImageFile, AppError> {
+    let row = sqlx::query("SELECT format, content_hash FROM images WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Image {id} not found")))?;
+
+    Ok(ImageFile {
+        id,
+        format: stored_format(row.get(0)),
+        content_hash: row.get(1),
+    })
+}
+
+/// SHA-256 of some bytes, in hexadecimal
+pub fn content_hash(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    let mut hash = String::with_capacity(digest.len() * 2);
+    for byte in digest.iter() {
+        write!(hash, "{byte:02x}").expect("writing to a String never fails");
+    }
+    hash
+}
+
+/// Storage key of a content addressed original. The first two characters of the
+/// hash are used as a sub folder, so no folder ends up with too many files.
+pub fn blob_key(hash: &str, format: ImageFormat) -> String {
+    format!("{BLOB_DIR}/{}/{hash}.{}", &hash[..2], format_name(format))
+}
+
+/// storage key of the untouched upload of a content addressed original in privacy mode
+pub fn private_blob_key(hash: &str, format: ImageFormat) -> String {
+    format!("{PRIVATE_DIR}/{hash}.{}", format_name(format))
+}
+
+/// Uploads and removals of the same content take turns, so a removal never
+/// deletes the files an upload has just written for the row it is inserting.
+/// The database is not locked meanwhile, the files are written outside of
+/// any transaction.
+pub async fn lock_content(hash: &str) -> MutexGuard<'static, ()> {
+    let stripe = u8::from_str_radix(hash.get(..2).unwrap_or_default(), 16).unwrap_or_default();
+    CONTENT_LOCKS[usize::from(stripe)].lock().await
+}
+
+/// whether an image holds this content
+pub async fn content_in_use(
+    executor: impl sqlx::SqliteExecutor<'_>,
+    hash: &str,
+) -> sqlx::Result<bool> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM images WHERE content_hash = ?)")
+        .bind(hash)
+        .fetch_one(executor)
+        .await
+}
+
+/// Images stored before content addressing are hashed and moved under their hash.
+/// An image with the same content as another one keeps its own file, rows are
+/// never merged behind the back of their users.
+pub async fn fill_content_hashes(pool: &Pool<Sqlite>, storage: &dyn Storage) -> anyhow::Result<()> {
+    let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM images WHERE content_hash IS NULL")
+        .fetch_all(pool)
+        .await?;
+
+    for id in ids {
+        let legacy = image_file(pool, id)
+            .await
+            .map_err(|e| anyhow::anyhow!("can't find image {id}: {e}"))?;
+        let public = storage.get(&legacy.key()).await?;
+        let private = storage.get(&legacy.private_key()).await?;
+        let Some(public) = public else {
+            eprintln!("can't find {} to hash it", legacy.key());
+            continue;
+        };
+        // the hash is the one of the upload, which is the private copy if there is one
+        let hash = content_hash(private.as_ref().unwrap_or(&public));
+
+        let duplicate: Option<i64> =
+            sqlx::query_scalar("SELECT id FROM images WHERE content_hash = ?")
+                .bind(&hash)
+                .fetch_optional(pool)
+                .await?;
+        if let Some(duplicate) = duplicate {
+            eprintln!("image {id} has the same content as image {duplicate}, it is not moved");
+            continue;
+        }
+
+        // Copy the files under their new name before recording it, and only then
+        // remove the old names: a crash in between leaves a file behind, never
+        // a row pointing at nothing.
+        let moved = ImageFile {
+            content_hash: Some(hash.clone()),
+            ..legacy.clone()
+        };
+        storage.put(&moved.key(), public).await?;
+        if let Some(private) = private {
+            storage.put(&moved.private_key(), private).await?;
+        }
+
+        sqlx::query("UPDATE images SET content_hash = ? WHERE id = ?")
+            .bind(&hash)
+            .bind(id)
+            .execute(pool)
+            .await?;
+
+        for key in [legacy.key(), legacy.private_key()] {
+            if let Err(e) = storage.delete(&key).await {
+                eprintln!("can't remove {key}: {e:#}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_format_falls_back_to_jpeg() {
+        assert_eq!(stored_format(Some("png")), ImageFormat::Png);
+        assert_eq!(stored_format(Some("webp")), ImageFormat::WebP);
+        assert_eq!(stored_format(None), ImageFormat::Jpeg);
+        assert_eq!(stored_format(Some("")), ImageFormat::Jpeg);
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    io::Cursor,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use image::{
+    codecs::avif::AvifEncoder, metadata::Orientation, ColorType, DynamicImage, ImageDecoder,
+    ImageFormat, ImageReader, Limits,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// AVIF encoding speed, from 1 (slowest, smallest) to 10. The default of the
+/// encoder takes seconds per image.
+const AVIF_SPEED: u8 = 8;
+/// AVIF quality, from 1 to 100. Looks like the JPEG of the default quality
+/// for about half of its size.
+const AVIF_QUALITY: u8 = 60;
+
+/// Decode a stored image, trusting the magic bytes, and turn it the right way up:
+/// phones save their photos sideways with an EXIF orientation tag.
+/// It was checked against the limits of the config when it was uploaded.
+pub fn load_image(bytes: &[u8]) -> anyhow::Result<DynamicImage> {
+    load_image_within(bytes, Limits::no_limits())
+}
+
+/// the same, refusing the images that need more memory than the limits allow
+pub fn load_image_within(bytes: &[u8], mut limits: Limits) -> anyhow::Result<DynamicImage> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits.clone());
+    let mut decoder = reader.into_decoder()?;
+    // the decoder only checks its own buffers, not the decoded image
+    limits.reserve(decoder.total_bytes())?;
+    // a broken EXIF block is not worth failing for, the image itself is fine
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// the unit of `DecodeBudget`
+const MIB: u64 = 1024 * 1024;
+
+/// Bounds the memory of the images decoded at the same time by the uploads,
+/// the jobs and `/resize` together. A decode holds the size of its pixels,
+/// in MiB, until the image is dropped.
+#[derive(Clone, Debug)]
+pub struct DecodeBudget {
+    semaphore: Arc<Semaphore>,
+    mebibytes: u32,
+}
+
+impl DecodeBudget {
+    pub fn new(max_bytes: u64) -> Self {
+        let mebibytes = u32::try_from(max_bytes.div_ceil(MIB))
+            .unwrap_or(u32::MAX)
+            .clamp(1, Semaphore::MAX_PERMITS as u32);
+        DecodeBudget {
+            semaphore: Arc::new(Semaphore::new(mebibytes as usize)),
+            mebibytes,
+        }
+    }
+
+    /// wait until `bytes` can be decoded, an image larger than the whole budget
+    /// waits for all of it
+    pub async fn reserve(&self, bytes: u64) -> OwnedSemaphorePermit {
+        let mebibytes = u32::try_from(bytes.div_ceil(MIB))
+            .unwrap_or(u32::MAX)
+            .min(self.mebibytes);
+        self.semaphore
+            .clone()
+            .acquire_many_owned(mebibytes)
+            .await
+            .expect("the decode budget is never closed")
+    }
+
+    /// the same for an encoded image, reading its size from its header
+    pub async fn reserve_for(&self, bytes: &[u8]) -> OwnedSemaphorePermit {
+        self.reserve(decoded_size(bytes)).await
+    }
+}
+
+/// Memory the pixels of an image take once decoded, read from its header.
+/// 0 when the header can't be read, decoding the image fails right away.
+pub fn decoded_size(bytes: &[u8]) -> u64 {
+    ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .map_or(0, |decoder| decoder.total_bytes())
+}
+
+/// encode an image into the given format
+pub fn encode_image(image: &DynamicImage, format: ImageFormat) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Cursor::new(Vec::new());
+    match format {
+        // JPEG has no alpha channel, the encoder refuses RGBA images
+        ImageFormat::Jpeg => {
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_to(&mut bytes, format)?;
+        }
+        ImageFormat::Avif => {
+            let encoder = AvifEncoder::new_with_speed_quality(&mut bytes, AVIF_SPEED, AVIF_QUALITY);
+            to_8_bits(image).write_with_encoder(encoder)?;
+        }
+        // the WebP encoder only knows 8 bits images
+        ImageFormat::WebP => to_8_bits(image).write_to(&mut bytes, format)?,
+        _ => image.write_to(&mut bytes, format)?,
+    }
+    Ok(bytes.into_inner())
+}
+
+/// the same image with 8 bits per channel, for the encoders that need it
+fn to_8_bits(image: &DynamicImage) -> std::borrow::Cow<'_, DynamicImage> {
+    match image.color() {
+        ColorType::L8 | ColorType::La8 | ColorType::Rgb8 | ColorType::Rgba8 => {
+            std::borrow::Cow::Borrowed(image)
+        }
+        _ => std::borrow::Cow::Owned(DynamicImage::ImageRgba8(image.to_rgba8())),
+    }
+}
+
+/// write a file next to its final path and rename it into place,
+/// so a concurrent reader never sees a half written file
+pub fn write_atomically(path: &std::path::Path, bytes: &[u8]) -> std::io::Result<()> {
+    // unique per process and per call, two threads may write the same file
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let unique = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".{}-{unique}.tmp", std::process::id()));
+    std::fs::write(&temp_path, bytes)?;
+    std::fs::rename(&temp_path, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp_path);
+    })
+}
+
+/// formats the generated renditions can be encoded into
+pub const OUTPUT_FORMATS: [ImageFormat; 4] = [
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+];
+
+/// find an output format from the name used in a query string
+pub fn format_from_name(name: &str) -> Option<ImageFormat> {
+    ImageFormat::from_extension(name).filter(|format| OUTPUT_FORMATS.contains(format))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use image::RgbaImage;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(width, height));
+        encode_image(&image, ImageFormat::Png).unwrap()
+    }
+
+    fn memory(max_alloc: u64) -> Limits {
+        let mut limits = Limits::no_limits();
+        limits.max_alloc = Some(max_alloc);
+        limits
+    }
+
+    #[test]
+    fn the_decoded_image_counts_against_the_limits() {
+        let bytes = png(64, 32);
+        let image = load_image_within(&bytes, memory(64 * 32 * 4)).unwrap();
+        assert_eq!((image.width(), image.height()), (64, 32));
+        assert!(load_image_within(&bytes, memory(64 * 32 * 4 - 1)).is_err());
+        assert!(load_image(&bytes).is_ok());
+    }
+
+    /// whether reserving `bytes` has to wait
+    async fn waits(budget: &DecodeBudget, bytes: u64) -> bool {
+        timeout(Duration::from_millis(20), budget.reserve(bytes))
+            .await
+            .is_err()
+    }
+
+    #[tokio::test]
+    async fn decodes_wait_for_their_share_of_the_budget() {
+        let budget = DecodeBudget::new(4 * MIB);
+        let first = budget.reserve(3 * MIB).await;
+        assert!(waits(&budget, 2 * MIB).await);
+        assert!(!waits(&budget, MIB).await);
+        drop(first);
+
+        // larger than the whole budget, waits for all of it
+        let whole = budget.reserve(100 * MIB).await;
+        assert!(waits(&budget, 1).await);
+        drop(whole);
+        assert!(!waits(&budget, 4 * MIB).await);
+
+        assert_eq!(decoded_size(&png(64, 32)), 64 * 32 * 4);
+        assert_eq!(decoded_size(b"not an image"), 0);
+    }
+}
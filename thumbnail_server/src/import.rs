@@ -0,0 +1,421 @@
+//! Importing a gallery delivered as a ZIP archive. Every image of the archive
+//! goes through `store_image`, like an upload. Its tags, title and description
+//! can be given by a `tags.csv` at the root of the archive:
+//!
+//! ```text
+//! file,tags,title,description
+//! holidays/IMG_1.jpg,"beach, sunset",First day,
+//! ```
+//!
+//! or by a sidecar JSON next to the image (`holidays/IMG_1.jpg.json` holding
+//! `{"tags": ["beach"], "title": "..."}`), which wins over `tags.csv`.
+//!
+//! An archive is not trusted:
+//! - it is kept in a temporary file rather than in memory, up to `IMPORT_MAX_BYTES`
+//! - an entry is read up to `UPLOAD_MAX_BYTES`, whatever size it claims (zip bombs)
+//! - at most `UPLOAD_MAX_FILES` images are imported, and their `tags.csv` and
+//!   sidecars are read up to `MAX_METADATA_TOTAL_BYTES` all together
+//! - nothing is extracted to the disk, and a name leaving the archive
+//!   (`../x`, `/etc/x`) is refused
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    path::{Component, Path},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{multipart::Field, Multipart},
+    Extension, Json,
+};
+use image::ImageFormat;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use tokio::{
+    io::AsyncWriteExt,
+    sync::mpsc::{self, Sender},
+    task::spawn_blocking,
+};
+use zip::ZipArchive;
+
+use crate::{
+    config::Config,
+    error::AppError,
+    imaging::DecodeBudget,
+    jobs::JobQueue,
+    similar::SimilarIndex,
+    storage::SharedStorage,
+    upload::{
+        finish_uploads, read_text, start_upload, ImageDetails, UploadContext, UploadResult,
+        UploadedFile,
+    },
+};
+
+/// the tags of the images, at the root of the archive
+const TAGS_FILE: &str = "tags.csv";
+
+/// extension of the sidecar of an image, added to its name
+const SIDECAR_EXTENSION: &str = "json";
+
+/// largest `tags.csv` or sidecar, in bytes
+const MAX_METADATA_BYTES: u64 = 1024 * 1024;
+
+/// largest `tags.csv` and sidecars all together once decompressed, in bytes.
+/// They are held in memory for the whole import.
+const MAX_METADATA_TOTAL_BYTES: u64 = 8 * 1024 * 1024;
+
+/// an archive with more entries is refused before anything is read
+const MAX_ENTRIES: usize = 100_000;
+
+/// What the archive tells about an image
+#[derive(Debug, Clone, Default)]
+struct ImageInfo {
+    tags: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+}
+
+/// A row of `tags.csv`, only `file` is required
+#[derive(Deserialize, Debug)]
+struct TagsRow {
+    /// path of the image inside the archive, or its bare file name
+    file: String,
+    tags: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+}
+
+/// A sidecar JSON, other fields (such as the ones of Google Takeout) are ignored
+#[derive(Deserialize, Debug)]
+struct Sidecar {
+    #[serde(default)]
+    tags: Vec<String>,
+    title: Option<String>,
+    description: Option<String>,
+}
+
+/// `POST /import/zip`, a multipart form with the `archive` and optional
+/// `tags` shared by every image. Answers like `/api/upload`, with one result
+/// per image of the archive.
+pub async fn import_zip(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(queue): Extension<JobQueue>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(index): Extension<SimilarIndex>,
+    Extension(budget): Extension<DecodeBudget>,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<UploadResult>>, AppError> {
+    let mut shared = ImageDetails::default();
+    let mut archive = None;
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(AppError::from_multipart)?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "tags" => shared.tags = read_text(&name, &mut field).await?,
+            "archive" => archive = Some(save_archive(&mut field, config.import_max_bytes).await?),
+            _ => return Err(AppError::BadRequest(format!("Unknown field: {name}"))),
+        }
+    }
+    let Some(archive) = archive else {
+        return Err(AppError::BadRequest(
+            "Missing field: archive is required".to_string(),
+        ));
+    };
+
+    // the archive is read by a blocking thread, an image at a time, and
+    // stored while the next one is extracted
+    let (sender, mut receiver) = mpsc::channel(1);
+    let (max_bytes, max_files) = (config.upload_max_bytes, config.upload_max_files);
+    let reader = spawn_blocking(move || read_archive(archive, max_bytes, max_files, sender));
+
+    let context = UploadContext::new(pool, config, storage, queue, index, budget);
+    let mut running = Vec::new();
+    while let Some(file) = receiver.recv().await {
+        running.push(start_upload(&context, &shared, file).await?);
+    }
+    reader.await??;
+
+    let uploads = finish_uploads(running).await;
+    Ok(Json(uploads.into_iter().map(UploadResult::from).collect()))
+}
+
+/// Save the archive in a temporary file, removed once it is closed.
+/// A ZIP is read from its end, it can't be handled as it arrives.
+async fn save_archive(field: &mut Field<'_>, max: u64) -> Result<std::fs::File, AppError> {
+    let mut file = tokio::fs::File::from_std(spawn_blocking(tempfile::tempfile).await??);
+    let mut size = 0;
+    while let Some(chunk) = field.chunk().await.map_err(AppError::from_multipart)? {
+        size += chunk.len() as u64;
+        if size > max {
+            return Err(AppError::PayloadTooLarge(format!(
+                "The archive is larger than {max} bytes"
+            )));
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(file.into_std().await)
+}
+
+/// Read the images of the archive and send them to be stored, with what
+/// `tags.csv` and the sidecars tell about them. An image that can't be
+/// read is sent with its error. Stops early when nobody listens anymore.
+fn read_archive(
+    file: std::fs::File,
+    max_bytes: usize,
+    max_files: usize,
+    sender: Sender<UploadedFile>,
+) -> Result<(), AppError> {
+    let mut archive = ZipArchive::new(file)
+        .map_err(|e| AppError::Unprocessable(format!("The archive can't be read: {e}")))?;
+    if archive.len() > MAX_ENTRIES {
+        return Err(AppError::Unprocessable(format!(
+            "The archive has more than {MAX_ENTRIES} entries"
+        )));
+    }
+    let infos = read_image_infos(&mut archive)?;
+
+    let mut files = 0;
+    for index in 0..archive.len() {
+        // look at the entry before decompressing it
+        let (name, path, is_symlink) = match archive.by_index_raw(index) {
+            Ok(entry) if entry.is_dir() => continue,
+            Ok(entry) => (
+                entry.name().ok().map(|name| name.to_string()),
+                entry.enclosed_name(),
+                entry.is_symlink(),
+            ),
+            Err(e) => {
+                let error = AppError::Unprocessable(format!("The file can't be read: {e}"));
+                if !send(&sender, None, Err(error), None) {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        let Some(path) = path else {
+            let error = AppError::BadRequest("The path leaves the archive".to_string());
+            if !send(&sender, name, Err(error), None) {
+                return Ok(());
+            }
+            continue;
+        };
+        if entry_kind(&path) != EntryKind::Image {
+            continue;
+        }
+        let path = path.to_string_lossy().into_owned();
+
+        files += 1;
+        let bytes = if files > max_files {
+            Err(AppError::PayloadTooLarge(format!(
+                "At most {max_files} images can be imported at once"
+            )))
+        } else if is_symlink {
+            Err(AppError::BadRequest(
+                "Symbolic links are not imported".to_string(),
+            ))
+        } else {
+            extract(&mut archive, index, max_bytes as u64).and_then(|bytes| {
+                bytes.ok_or_else(|| {
+                    AppError::PayloadTooLarge(format!("The image is larger than {max_bytes} bytes"))
+                })
+            })
+        };
+
+        let file_name = Path::new(&path)
+            .file_name()
+            .map(|name| name.to_string_lossy());
+        let info = infos
+            .get(&path)
+            .or_else(|| infos.get(file_name?.as_ref()))
+            .cloned();
+        if !send(&sender, Some(path), bytes, info) {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// give an image to the async side, `false` once it stopped listening
+fn send(
+    sender: &Sender<UploadedFile>,
+    name: Option<String>,
+    bytes: Result<Vec<u8>, AppError>,
+    info: Option<ImageInfo>,
+) -> bool {
+    let info = info.unwrap_or_default();
+    let file = UploadedFile {
+        name,
+        bytes,
+        tags: info.tags,
+        title: info.title,
+        description: info.description,
+    };
+    sender.blocking_send(file).is_ok()
+}
+
+/// What an entry of the archive holds
+#[derive(Debug, PartialEq, Eq)]
+enum EntryKind {
+    Image,
+    Tags,
+    Sidecar,
+    /// hidden files (`.DS_Store`, `__MACOSX/`...) and whatever is not named
+    /// like an image (`README.txt`, a nested `.zip`...), not imported
+    Ignored,
+}
+
+fn entry_kind(path: &Path) -> EntryKind {
+    let hidden = path.components().any(|component| match component {
+        Component::Normal(part) => {
+            let part = part.to_string_lossy();
+            part.starts_with('.') || part == "__MACOSX"
+        }
+        _ => false,
+    });
+    if hidden {
+        EntryKind::Ignored
+    } else if path == Path::new(TAGS_FILE) {
+        EntryKind::Tags
+    } else if path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case(SIDECAR_EXTENSION))
+    {
+        EntryKind::Sidecar
+    } else if ImageFormat::from_path(path).is_ok() {
+        // the content is checked by `store_image`, like an upload
+        EntryKind::Image
+    } else {
+        EntryKind::Ignored
+    }
+}
+
+/// Decompress an entry up to `max` bytes, `None` when it is larger.
+/// The size written in the archive can lie, it is checked while reading.
+fn extract(
+    archive: &mut ZipArchive<std::fs::File>,
+    index: usize,
+    max: u64,
+) -> Result<Option<Vec<u8>>, AppError> {
+    let entry = archive
+        .by_index(index)
+        .map_err(|e| AppError::Unprocessable(format!("The file can't be extracted: {e}")))?;
+    let mut bytes = Vec::new();
+    entry
+        .take(max + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| AppError::Unprocessable(format!("The file can't be extracted: {e}")))?;
+    Ok((bytes.len() as u64 <= max).then_some(bytes))
+}
+
+/// Read `tags.csv` and the sidecars, by path of the image they describe.
+/// The rows of `tags.csv` can also give a bare file name, a sidecar whose
+/// image is not in the archive is skipped. A broken one, or more than
+/// `MAX_METADATA_TOTAL_BYTES` of them, fails the whole import before
+/// anything is stored.
+fn read_image_infos(
+    archive: &mut ZipArchive<std::fs::File>,
+) -> Result<HashMap<String, ImageInfo>, AppError> {
+    // the names are known before anything is decompressed
+    let mut images = HashSet::new();
+    let mut metadata = Vec::new();
+    for index in 0..archive.len() {
+        let Ok(entry) = archive.by_index_raw(index) else {
+            continue;
+        };
+        let Some(path) = entry.enclosed_name().filter(|_| !entry.is_dir()) else {
+            continue;
+        };
+        match entry_kind(&path) {
+            EntryKind::Image => {
+                images.insert(path.to_string_lossy().into_owned());
+            }
+            kind @ (EntryKind::Tags | EntryKind::Sidecar) => metadata.push((index, path, kind)),
+            EntryKind::Ignored => {}
+        }
+    }
+
+    let mut infos = HashMap::new();
+    let mut sidecars = Vec::new();
+    let mut budget = MAX_METADATA_TOTAL_BYTES;
+    for (index, path, kind) in metadata {
+        // the sidecar of `IMG_1.jpg` is `IMG_1.jpg.json`
+        let image = path.with_extension("").to_string_lossy().into_owned();
+        if kind == EntryKind::Sidecar && !images.contains(&image) {
+            continue;
+        }
+
+        let name = path.to_string_lossy().into_owned();
+        let Some(bytes) = extract(archive, index, MAX_METADATA_BYTES.min(budget))? else {
+            return Err(AppError::PayloadTooLarge(if budget < MAX_METADATA_BYTES {
+                format!(
+                    "tags.csv and the sidecars are larger than {MAX_METADATA_TOTAL_BYTES} bytes"
+                )
+            } else {
+                format!("{name} is larger than {MAX_METADATA_BYTES} bytes")
+            }));
+        };
+        budget -= bytes.len() as u64;
+
+        if kind == EntryKind::Tags {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(bytes.as_slice());
+            for row in reader.deserialize::<TagsRow>() {
+                let row = row.map_err(|e| AppError::Unprocessable(format!("{name}: {e}")))?;
+                let file = row.file.trim_start_matches("./").to_string();
+                infos.insert(
+                    file,
+                    ImageInfo {
+                        tags: row.tags,
+                        title: row.title,
+                        description: row.description,
+                    },
+                );
+            }
+        } else {
+            let sidecar: Sidecar = serde_json::from_slice(&bytes)
+                .map_err(|e| AppError::Unprocessable(format!("{name}: {e}")))?;
+            sidecars.push((image, sidecar));
+        }
+    }
+
+    // sidecars win over tags.csv, wherever they are in the archive
+    for (image, sidecar) in sidecars {
+        infos.insert(
+            image,
+            ImageInfo {
+                tags: Some(sidecar.tags.join(",")),
+                title: sidecar.title,
+                description: sidecar.description,
+            },
+        );
+    }
+    Ok(infos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_images_are_imported() {
+        let kind = |path: &str| entry_kind(Path::new(path));
+        assert_eq!(kind("IMG_1.jpg"), EntryKind::Image);
+        assert_eq!(kind("holidays/IMG_2.JPEG"), EntryKind::Image);
+        assert_eq!(kind("scan.tiff"), EntryKind::Image);
+        assert_eq!(kind("tags.csv"), EntryKind::Tags);
+        assert_eq!(kind("holidays/IMG_2.JPEG.json"), EntryKind::Sidecar);
+        assert_eq!(kind("holidays/tags.csv"), EntryKind::Ignored);
+        assert_eq!(kind("README.txt"), EntryKind::Ignored);
+        assert_eq!(kind("more.zip"), EntryKind::Ignored);
+        assert_eq!(kind("Makefile"), EntryKind::Ignored);
+        assert_eq!(kind(".hidden.jpg"), EntryKind::Ignored);
+        assert_eq!(kind("__MACOSX/._IMG_1.jpg"), EntryKind::Ignored);
+    }
+}
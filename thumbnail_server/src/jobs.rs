@@ -0,0 +1,333 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use axum::{extract::Path, Extension, Json};
+use serde::Serialize;
+use sqlx::{prelude::FromRow, Sqlite, SqlitePool, Transaction};
+use tokio::sync::Notify;
+
+use crate::{
+    config::Config,
+    delete,
+    error::AppError,
+    files::image_file,
+    imaging::DecodeBudget,
+    make_thumbnail,
+    metadata::save_metadata,
+    privacy::{read_stored_metadata, strip_stored_image},
+    resize,
+    similar::SimilarIndex,
+    storage::SharedStorage,
+};
+
+/// how long an idle worker sleeps before looking for jobs whose backoff has expired
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a job does
+#[derive(sqlx::Type, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    /// generate the thumbnails of every preset
+    Thumbnail,
+    /// read the dimensions and EXIF of an image uploaded before they were recorded
+    Metadata,
+    /// privacy mode: keep the original aside and serve a copy without metadata
+    #[sqlx(rename = "strip_metadata")]
+    #[serde(rename = "strip_metadata")]
+    StripMetadata,
+}
+
+/// Where a job is in its life
+#[derive(sqlx::Type, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// waiting for a worker, possibly until `run_after`
+    Pending,
+    /// claimed by a worker
+    Running,
+    /// finished successfully
+    Done,
+    /// gave up after too many attempts, see `last_error`
+    Failed,
+}
+
+/// A row of the `jobs` table, also the JSON returned by `/jobs/:id`
+#[derive(Serialize, FromRow, Debug)]
+pub struct Job {
+    pub id: i64,
+    pub kind: JobKind,
+    pub image_id: i64,
+    pub status: JobStatus,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub run_after: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// Handle on the worker pool, used to wake the workers up when a job is added
+#[derive(Clone)]
+pub struct JobQueue {
+    notify: Arc<Notify>,
+}
+
+impl JobQueue {
+    /// Start the workers. Jobs left running by a previous process (crash, restart)
+    /// are put back in the queue first.
+    pub async fn start(
+        pool: SqlitePool,
+        config: Arc<Config>,
+        storage: SharedStorage,
+        index: SimilarIndex,
+        budget: DecodeBudget,
+    ) -> anyhow::Result<Self> {
+        sqlx::query(
+            "UPDATE jobs SET status = 'pending', updated_at = unixepoch() WHERE status = 'running'",
+        )
+        .execute(&pool)
+        .await?;
+
+        let queue = JobQueue {
+            notify: Arc::new(Notify::new()),
+        };
+        for _ in 0..config.job_workers {
+            tokio::spawn(worker(
+                pool.clone(),
+                config.clone(),
+                storage.clone(),
+                index.clone(),
+                budget.clone(),
+                queue.notify.clone(),
+            ));
+        }
+        Ok(queue)
+    }
+
+    /// tell the workers that new jobs are waiting, call it after the job is committed
+    pub fn wake(&self) {
+        self.notify.notify_waiters();
+    }
+}
+
+/// add a job to the queue, inside the transaction that creates the work to do
+pub async fn enqueue(
+    tx: &mut Transaction<'_, Sqlite>,
+    kind: JobKind,
+    image_id: i64,
+) -> anyhow::Result<i64> {
+    let id = sqlx::query_scalar("INSERT INTO jobs (kind, image_id) VALUES (?, ?) RETURNING id")
+        .bind(kind)
+        .bind(image_id)
+        .fetch_one(&mut **tx)
+        .await?;
+    Ok(id)
+}
+
+/// add a job to the queue unless the same work is already waiting or running
+pub async fn enqueue_once(pool: &SqlitePool, kind: JobKind, image_id: i64) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT INTO jobs (kind, image_id)
+         SELECT ?1, ?2 WHERE NOT EXISTS (
+             SELECT 1 FROM jobs
+             WHERE kind = ?1 AND image_id = ?2 AND status IN ('pending', 'running')
+         )",
+    )
+    .bind(kind)
+    .bind(image_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// get the status of a job, so the uploader can wait for its thumbnail
+pub async fn get_job(
+    Path(id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Json<Job>, AppError> {
+    let job = sqlx::query_as::<_, Job>("SELECT * FROM jobs WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Job {id} not found")))?;
+    Ok(Json(job))
+}
+
+/// one worker: claim a job, run it, record the outcome, repeat
+async fn worker(
+    pool: SqlitePool,
+    config: Arc<Config>,
+    storage: SharedStorage,
+    index: SimilarIndex,
+    budget: DecodeBudget,
+    notify: Arc<Notify>,
+) {
+    loop {
+        // register before looking at the table, so a wake up that happens
+        // while we are claiming is not lost
+        let notified = notify.notified();
+
+        match claim(&pool).await {
+            Ok(Some(job)) => {
+                let result = run(&pool, &config, &storage, &index, &budget, &job).await;
+                if let Err(e) = finish(&pool, &config, &job, result).await {
+                    eprintln!("failed to record the outcome of job {}: {e:?}", job.id);
+                }
+            }
+            Ok(None) => {
+                tokio::select! {
+                    _ = notified => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+            }
+            Err(e) => {
+                eprintln!("failed to claim a job: {e:?}");
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Atomically take the oldest runnable job. SQLite only has one writer at a time,
+/// so two workers can't claim the same job.
+async fn claim(pool: &SqlitePool) -> anyhow::Result<Option<Job>> {
+    let job = sqlx::query_as::<_, Job>(
+        "UPDATE jobs
+         SET status = 'running', attempts = attempts + 1, updated_at = unixepoch()
+         WHERE id = (
+             SELECT id FROM jobs
+             WHERE status = 'pending' AND run_after <= unixepoch()
+             ORDER BY run_after, id
+             LIMIT 1
+         )
+         RETURNING *",
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// do the work of a job
+async fn run(
+    pool: &SqlitePool,
+    config: &Config,
+    storage: &SharedStorage,
+    index: &SimilarIndex,
+    budget: &DecodeBudget,
+    job: &Job,
+) -> anyhow::Result<()> {
+    match job.kind {
+        JobKind::Thumbnail => {
+            let id = job.image_id;
+            let file = image_file(pool, id)
+                .await
+                .map_err(|e| anyhow::anyhow!("can't find image {id}: {e}"))?;
+            let hash = make_thumbnail(
+                storage.as_ref(),
+                budget,
+                &file,
+                &config.thumbnail_presets,
+                &config.thumbnail_formats,
+            )
+            .await?;
+            // SQLite has no unsigned 64 bits type. The new time gives
+            // the thumbnails a new ETag
+            let updated = sqlx::query(
+                "UPDATE images SET perceptual_hash = ?, thumbnails_at = unixepoch() WHERE id = ?",
+            )
+            .bind(hash as i64)
+            .bind(id)
+            .execute(pool)
+            .await?;
+            // the image was deleted while its thumbnails were made
+            if updated.rows_affected() == 0 {
+                delete::remove_thumbnails(storage.as_ref(), &config.thumbnail_presets, id).await?;
+                return Ok(());
+            }
+            index.insert(id, hash);
+            // the renditions are stale too when the thumbnails are made again
+            resize::remove_cached_renditions(storage.as_ref(), id).await?;
+        }
+        JobKind::Metadata => {
+            let id = job.image_id;
+            let file = image_file(pool, id)
+                .await
+                .map_err(|e| anyhow::anyhow!("can't find image {id}: {e}"))?;
+            let metadata = read_stored_metadata(storage.as_ref(), &file)
+                .await
+                .with_context(|| format!("can't read {}", file.key()))?;
+            save_metadata(pool, id, &metadata).await?;
+        }
+        JobKind::StripMetadata => {
+            let id = job.image_id;
+            let file = image_file(pool, id)
+                .await
+                .map_err(|e| anyhow::anyhow!("can't find image {id}: {e}"))?;
+            let (size, metadata) = strip_stored_image(storage.as_ref(), budget, &file).await?;
+
+            let mut tx = pool.begin().await?;
+            sqlx::query(
+                "UPDATE images SET size = ?, metadata_stripped = 1, file_modified_at = unixepoch()
+                 WHERE id = ?",
+            )
+            .bind(size as i64)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+            save_metadata(&mut *tx, id, &metadata).await?;
+            tx.commit().await?;
+        }
+    }
+    Ok(())
+}
+
+/// mark a job as done, or schedule a retry with an exponential backoff,
+/// or give up once it failed too many times
+async fn finish(
+    pool: &SqlitePool,
+    config: &Config,
+    job: &Job,
+    result: anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    match result {
+        Ok(()) => {
+            sqlx::query(
+                "UPDATE jobs SET status = 'done', last_error = NULL, updated_at = unixepoch()
+                 WHERE id = ?",
+            )
+            .bind(job.id)
+            .execute(pool)
+            .await?;
+        }
+        Err(error) => {
+            let error = format!("{error:#}");
+            eprintln!(
+                "job {} ({:?} of image {}) failed: {error}",
+                job.id, job.kind, job.image_id
+            );
+
+            let status = if job.attempts >= config.job_max_attempts {
+                JobStatus::Failed
+            } else {
+                JobStatus::Pending
+            };
+            // 2s, 4s, 8s... capped to one hour
+            let exponent = job.attempts.clamp(1, 11) as u32;
+            let delay = 2_i64.pow(exponent).min(3600);
+
+            sqlx::query(
+                "UPDATE jobs
+                 SET status = ?, last_error = ?, run_after = unixepoch() + ?, updated_at = unixepoch()
+                 WHERE id = ?",
+            )
+            .bind(status)
+            .bind(error)
+            .bind(delay)
+            .bind(job.id)
+            .execute(pool)
+            .await?;
+        }
+    }
+    Ok(())
+}
@@ -1,23 +1,63 @@
-use anyhow::Ok;
+use std::sync::Arc;
+
 use axum::{
     body::Body,
-    extract::{Multipart, Path},
-    http::{header, HeaderMap},
-    response::{Html, IntoResponse},
+    extract::{rejection::QueryRejection, DefaultBodyLimit, Path, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, Response},
     routing::{get, post},
-    Extension, Form, Json, Router,
+    Extension, Json, Router,
 };
-use futures::TryStreamExt;
+use caching::VersionQuery;
+use config::{Config, ThumbnailPreset};
+use error::{AppError, HtmlError};
+use files::{image_file, stored_format, ImageFile, LEGACY_FORMAT};
+use image::{DynamicImage, ImageFormat};
+use imaging::{encode_image, load_image, DecodeBudget};
+use jobs::{JobKind, JobQueue};
+use metadata::ImageMetadata;
+use pagination::{ImagePage, Order, PageRequest, Sort};
+use search::ImageFilter;
 use serde::{Deserialize, Serialize};
+use similar::SimilarIndex;
 use sqlx::{prelude::FromRow, Pool, Row, Sqlite};
+use storage::{SharedStorage, Storage};
 use tokio::task::spawn_blocking;
-use tokio_util::io::ReaderStream;
+
+mod admin;
+mod caching;
+mod config;
+mod delete;
+mod edit;
+mod error;
+mod files;
+mod imaging;
+mod import;
+mod jobs;
+mod metadata;
+mod negotiation;
+mod pagination;
+mod privacy;
+mod query;
+mod ranges;
+mod resize;
+mod search;
+mod similar;
+mod storage;
+mod tags;
+#[cfg(test)]
+mod test_util;
+mod upload;
+mod validation;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Read the .env file and build environment variables
     dotenv::dotenv()?;
 
+    // read the server configuration (thumbnail presets...)
+    let config = Arc::new(Config::from_env()?);
+
     // get database url
     let db_url = std::env::var("DATABASE_URL")?;
 
@@ -27,18 +67,94 @@ async fn main() -> anyhow::Result<()> {
     // Run migrations
     sqlx::migrate!("./migrations").run(&pool).await?;
 
-    // Catch up on missing thumbnails
-    fill_missing_thumbnails(&pool).await?;
+    // Lowercase the tags the migration couldn't, SQLite only knows ASCII
+    tags::normalize_existing_tags(&pool).await?;
+
+    // Open the folder or the bucket holding the images
+    let storage = storage::open(&config.storage)?;
+
+    // Find the real format of the images uploaded before it was recorded
+    detect_legacy_formats(&pool, storage.as_ref()).await?;
+
+    // Record the size of the images uploaded before it was recorded
+    fill_missing_sizes(&pool, storage.as_ref()).await?;
+
+    // Move the images stored under their id under their content hash
+    files::fill_content_hashes(&pool, storage.as_ref()).await?;
+
+    // Finish removing the files of the images deleted while the storage failed
+    delete::remove_deleted_files(&pool, &config, storage.as_ref()).await?;
+
+    // Forget the renditions cached before each image had its own cache folder
+    resize::remove_legacy_renditions(storage.as_ref()).await?;
+
+    // Keep the perceptual hashes at hand for the near-duplicate searches
+    let index = SimilarIndex::load(&pool).await?;
+
+    // Share the memory of the decoded images between the uploads, jobs and renditions
+    let budget = DecodeBudget::new(config.decode_max_memory);
+    let renderer = resize::Renderer::new(config.resize_concurrency, budget.clone());
+
+    // Start the workers generating the thumbnails in the background
+    let queue = JobQueue::start(
+        pool.clone(),
+        config.clone(),
+        storage.clone(),
+        index.clone(),
+        budget.clone(),
+    )
+    .await?;
+
+    // Catch up on missing thumbnails and metadata
+    fill_missing_thumbnails(&pool, &config, storage.as_ref()).await?;
+    fill_missing_metadata(&pool).await?;
+    if config.strip_metadata {
+        strip_existing_images(&pool).await?;
+    }
+    queue.wake();
 
     // Build Axum with an "extension" to hold the database connection pool
     let app = Router::new()
         .route("/", get(index_page))
-        .route("/upload", post(uploader))
-        .route("/image/:id", get(get_image))
+        // every field of the upload form is read with its own limit
+        .route(
+            "/upload",
+            post(upload::uploader).layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/api/upload",
+            post(upload::api_uploader).layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/image/:id",
+            get(get_image)
+                .patch(edit::update_image)
+                .delete(delete::delete_image),
+        )
+        .route("/image/:id/delete", post(delete::delete_image_form))
+        .route(
+            "/import/zip",
+            post(import::import_zip).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/image/:id/meta", get(metadata::get_metadata))
+        .route("/image/:id/display", get(resize::display_image))
+        .route("/image/:id/similar", get(similar::similar_images))
         .route("/thumb/:id", get(get_thumbnail))
+        .route("/thumb/:id/:preset", get(get_thumbnail_preset))
+        .route("/resize/:id", get(resize::resize_image))
         .route("/images", get(list_images))
-        .route("/search", post(search_images))
-        .layer(Extension(pool));
+        .route("/search", post(search::search_images))
+        .route("/api/search", get(search::api_search))
+        .route("/tags", get(tags::list_tags))
+        .route("/jobs/:id", get(jobs::get_job))
+        .route("/admin/image/:id/original", get(admin::get_original))
+        .layer(Extension(pool))
+        .layer(Extension(config))
+        .layer(Extension(queue))
+        .layer(Extension(storage))
+        .layer(Extension(index))
+        .layer(Extension(budget))
+        .layer(Extension(renderer));
 
     // run our app with hyper, listening globally on port 3000
     let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
@@ -62,188 +178,386 @@ async fn main() -> anyhow::Result<()> {
 //     format!("{count} images in the database")
 // }
 
-async fn index_page() -> Html<String> {
+async fn index_page() -> Result<Html<String>, HtmlError> {
     let path = std::path::Path::new("src/index.html");
-    let content = tokio::fs::read_to_string(path).await.unwrap();
-    Html(content)
+    let content = tokio::fs::read_to_string(path).await?;
+    Ok(Html(content))
 }
 
-async fn insert_image_into_database(pool: &sqlx::SqlitePool, tags: &str) -> anyhow::Result<i64> {
-    let row = sqlx::query("INSERT INTO images (tags) VALUES (?) RETURNING id")
-        .bind(tags)
-        .fetch_one(pool)
-        .await?;
-    Ok(row.get(0))
-}
+/// Open a file from the storage and stream it back as the response body.
+/// With a `range` (see `ranges::requested_range`) only the asked parts are sent.
+async fn serve_file(
+    storage: &SharedStorage,
+    key: &str,
+    content_type: &str,
+    range: Option<&str>,
+) -> Result<Response, AppError> {
+    let partial = match range {
+        Some(range) => ranges::serve_ranges(storage, key, content_type, range).await?,
+        None => None,
+    };
+    let mut response = match partial {
+        Some(response) => response,
+        None => {
+            // a missing file means the image doesn't exist
+            let Some(file) = storage.stream(key).await? else {
+                return Err(AppError::NotFound(format!("{key} not found")));
+            };
+
+            // build the response body
+            Response::builder()
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_LENGTH, file.size)
+                .body(Body::from_stream(file.body))?
+        }
+    };
 
-async fn save_image(id: i64, bytes: &[u8]) -> anyhow::Result<()> {
-    // Check that the images folder exists and is a directory
-    // If it doesn't, create it.
-    let base_path = std::path::Path::new("images");
-    if !base_path.exists() || !base_path.is_dir() {
-        tokio::fs::create_dir_all(base_path).await?;
+    // a download that was cut can be resumed
+    let headers = response.headers_mut();
+    headers.insert(header::ACCEPT_RANGES, "bytes".parse()?);
+    // a 416 only tells the size of the file, it has nothing to save
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        return Ok(response);
     }
 
-    // Use "join" to create a path to the image file. Join is platform aware,
-    // it will handle the differences between Windows and Linux.
-    let image_path = base_path.join(format!("{id}.jpg"));
-    if image_path.exists() {
-        // The file exists. That shouldn't happen.
-        anyhow::bail!("File already exists");
-    }
+    // the content_disposition is used to convey additional information about how to process the response payload,
+    // and also can be used to attach additional metadata,
+    // such as the filename to use when saving the response payload locally
+    let filename = key.rsplit('/').next().unwrap_or(key);
+    let attachment = format!("filename={filename}");
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_DISPOSITION, attachment.parse()?);
+    Ok(response)
+}
 
-    // Write the image to the file
-    tokio::fs::write(image_path, bytes).await?;
-    Ok(())
+/// storage key of an original stored before content addressing, named after
+/// the image id. The extension follows its format.
+fn original_key(id: i64, format: ImageFormat) -> String {
+    format!("{id}.{}", format_name(format))
 }
 
-async fn uploader(
+/// name of a format as stored in the database, also used as file extension
+fn format_name(format: ImageFormat) -> &'static str {
+    format.extensions_str()[0]
+}
+
+/// get image from the data base and return the response
+async fn get_image(
+    Path(id): Path<i64>,
     Extension(pool): Extension<sqlx::SqlitePool>,
-    mut multipart: Multipart,
-) -> Html<String> {
-    // "None" means "no tags yet"
-    let mut tags = None;
-    let mut image = None;
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap().to_string();
-        let data = field.bytes().await.unwrap();
-
-        match name.as_str() {
-            // Using Some means we can check we received it
-            "tags" => tags = Some(String::from_utf8(data.to_vec()).unwrap()),
-            "image" => image = Some(data.to_vec()),
-            _ => panic!("Unknown field: {name}"),
+    Extension(config): Extension<Arc<Config>>,
+    Extension(storage): Extension<SharedStorage>,
+    headers: HeaderMap,
+    query: Result<Query<VersionQuery>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let Query(query) = query.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let file = image_file(&pool, id).await?;
+    let policy = caching::image_version(&pool, id)
+        .await?
+        .map(|version| version.policy(None, &query));
+    let content_type = file.format.to_mime_type();
+    caching::serve_cached(
+        &storage,
+        &file.key(),
+        content_type,
+        &headers,
+        policy,
+        &config,
+    )
+    .await
+}
+
+/// every format a thumbnail can be made in, configured or not
+const THUMBNAIL_FORMATS: [ImageFormat; 3] =
+    [ImageFormat::Jpeg, ImageFormat::WebP, ImageFormat::Avif];
+
+/// storage key of the thumbnail of an image for a given preset and format
+fn thumbnail_key(id: i64, preset: &ThumbnailPreset, format: ImageFormat) -> String {
+    format!("{id}_{}.{}", preset.name, format_name(format))
+}
+
+/// get the default thumbnail from the data base and return the response
+async fn get_thumbnail(
+    Path(id): Path<i64>,
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(storage): Extension<SharedStorage>,
+    headers: HeaderMap,
+    query: Result<Query<VersionQuery>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let Query(query) = query.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let preset = config.default_preset();
+    serve_thumbnail(&pool, &config, &storage, id, preset, &headers, &query).await
+}
+
+/// get the thumbnail of an image for a named preset (small, medium...)
+async fn get_thumbnail_preset(
+    Path((id, preset)): Path<(i64, String)>,
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(storage): Extension<SharedStorage>,
+    headers: HeaderMap,
+    query: Result<Query<VersionQuery>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let Query(query) = query.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let Some(preset) = config.preset(&preset) else {
+        return Err(AppError::NotFound(format!(
+            "Unknown thumbnail preset: {preset}"
+        )));
+    };
+    serve_thumbnail(&pool, &config, &storage, id, preset, &headers, &query).await
+}
+
+/// Serve a thumbnail in the best format the client accepts, with its caching
+/// headers. Every preset and format shares the version of the thumbnails of the image.
+async fn serve_thumbnail(
+    pool: &sqlx::SqlitePool,
+    config: &Config,
+    storage: &SharedStorage,
+    id: i64,
+    preset: &ThumbnailPreset,
+    headers: &HeaderMap,
+    query: &VersionQuery,
+) -> Result<Response, AppError> {
+    // a variant is missing until the job of an old image runs again,
+    // or when it was not smaller than the JPEG
+    let mut format = ImageFormat::Jpeg;
+    for preferred in negotiation::preferred_formats(headers, &config.thumbnail_formats) {
+        if preferred == ImageFormat::Jpeg
+            || storage
+                .exists(&thumbnail_key(id, preset, preferred))
+                .await?
+        {
+            format = preferred;
+            break;
+        }
+    }
+
+    let variant = format!("{}.{}", preset.name, format_name(format));
+    let policy = caching::thumbnails_version(pool, id)
+        .await?
+        .map(|version| version.policy(Some(&variant), query));
+    let key = thumbnail_key(id, preset, format);
+    let mut response = caching::serve_cached(
+        storage,
+        &key,
+        format.to_mime_type(),
+        headers,
+        policy,
+        config,
+    )
+    .await?;
+    // the same URL gives another format to another client
+    response
+        .headers_mut()
+        .insert(header::VARY, "Accept".parse()?);
+    Ok(response)
+}
+
+/// create the thumbnails of every preset and format of an image,
+/// returns its perceptual hash as the image is decoded anyway
+async fn make_thumbnail(
+    storage: &dyn Storage,
+    budget: &DecodeBudget,
+    file: &ImageFile,
+    presets: &[ThumbnailPreset],
+    formats: &[ImageFormat],
+) -> anyhow::Result<u64> {
+    // read all image into vec of bytes
+    let key = file.key();
+    let Some(image_bytes) = storage.get(&key).await? else {
+        anyhow::bail!("{key} not found");
+    };
+
+    let (presets, formats) = (presets.to_vec(), formats.to_vec());
+    let decoding = budget.reserve_for(&image_bytes).await;
+    let (thumbnails, hash) = spawn_blocking(move || {
+        let _decoding = decoding;
+        let image = load_image(&image_bytes)?;
+        let thumbnails = render_thumbnails(&image, &presets, &formats)?;
+        Ok::<_, anyhow::Error>((thumbnails, similar::perceptual_hash(&image)))
+    })
+    .await??;
+
+    for thumbnail in thumbnails {
+        let key = thumbnail_key(file.id, &thumbnail.preset, thumbnail.format);
+        match thumbnail.bytes {
+            Some(bytes) => storage.put(&key, bytes).await?,
+            // don't leave a variant made from an older reading of the image
+            None => storage.delete(&key).await?,
         }
     }
 
-    if let (Some(tags), Some(image)) = (tags, image) {
-        // Destructuring both Options at once
-        let new_image_id = insert_image_into_database(&pool, &tags).await.unwrap();
-        save_image(new_image_id, &image).await.unwrap();
-        spawn_blocking(move || {
-            make_thumbnail(new_image_id).unwrap();
+    Ok(hash)
+}
+
+/// A thumbnail encoded by `render_thumbnails`
+struct RenderedThumbnail {
+    preset: ThumbnailPreset,
+    format: ImageFormat,
+    /// `None` for a variant that is not worth keeping
+    bytes: Option<Vec<u8>>,
+}
+
+/// Encode a JPEG thumbnail of an image for every preset, and its variants in
+/// the other formats. A variant that is not smaller than the JPEG is useless
+/// and left out (lossless WebP often loses against JPEG on photos).
+fn render_thumbnails(
+    image: &DynamicImage,
+    presets: &[ThumbnailPreset],
+    formats: &[ImageFormat],
+) -> anyhow::Result<Vec<RenderedThumbnail>> {
+    let mut thumbnails = Vec::new();
+    for preset in presets {
+        let thumbnail = image.thumbnail(preset.width, preset.height);
+        let jpeg = encode_image(&thumbnail, ImageFormat::Jpeg)?;
+
+        for &format in formats {
+            // the JPEG is enough to show the image, a variant failing is not an error
+            let variant = match encode_image(&thumbnail, format) {
+                Ok(bytes) => Some(bytes).filter(|bytes| bytes.len() < jpeg.len()),
+                Err(e) => {
+                    eprintln!("can't encode a {format:?} thumbnail: {e:#}");
+                    None
+                }
+            };
+            thumbnails.push(RenderedThumbnail {
+                preset: preset.clone(),
+                format,
+                bytes: variant,
+            });
+        }
+        thumbnails.push(RenderedThumbnail {
+            preset: preset.clone(),
+            format: ImageFormat::Jpeg,
+            bytes: Some(jpeg),
         });
-    } else {
-        panic!("Missing field");
     }
-    // redirect user after upload image
-    let path = std::path::Path::new("src/redirect.html");
-    let content = tokio::fs::read_to_string(path).await.unwrap();
-    Html(content)
+    Ok(thumbnails)
 }
 
-/// get image from the data base and return the response
-async fn get_image(Path(id): Path<i64>) -> impl IntoResponse {
-    let filename = format!("images/{id}.jpg");
-    let attachment = format!("filename={filename}");
+/// Images uploaded before the format was recorded were all saved as `{id}.jpg`,
+/// whatever they really were. Look at their magic bytes, give them the right
+/// extension and record their format.
+async fn detect_legacy_formats(pool: &Pool<Sqlite>, storage: &dyn Storage) -> anyhow::Result<()> {
+    let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM images WHERE format IS NULL")
+        .fetch_all(pool)
+        .await?;
 
-    // create hash map of headers
-    let mut headers = HeaderMap::new();
+    for id in ids {
+        let legacy_key = original_key(id, LEGACY_FORMAT);
+        let Some(bytes) = storage.get(&legacy_key).await? else {
+            eprintln!("can't find {legacy_key} to detect its format");
+            continue;
+        };
+        let format = image::guess_format(&bytes).unwrap_or(LEGACY_FORMAT);
+
+        // storages can't rename, the file is copied under its new name
+        if format != LEGACY_FORMAT {
+            storage.put(&original_key(id, format), bytes).await?;
+            storage.delete(&legacy_key).await?;
+        }
+        sqlx::query("UPDATE images SET format = ? WHERE id = ?")
+            .bind(format_name(format))
+            .bind(id)
+            .execute(pool)
+            .await?;
+    }
 
-    // add content_type header
-    headers.insert(
-        header::CONTENT_TYPE,
-        header::HeaderValue::from_static("image/jpeg"),
-    );
-    // add the content_disposition, ed to convey additional information about how to process the response payload,
-    // and also can be used to attach additional metadata,
-    // such as the filename to use when saving the response payload locally
-    headers.insert(
-        header::CONTENT_DISPOSITION,
-        header::HeaderValue::from_str(&attachment).unwrap(),
-    );
-
-    // get the file name using tokio
-    let file = tokio::fs::File::open(&filename).await.unwrap();
-
-    // build the response body
-    axum::response::Response::builder()
-        .header(
-            header::CONTENT_TYPE,
-            header::HeaderValue::from_static("image/jpeg"),
-        )
-        .header(
-            header::CONTENT_DISPOSITION,
-            header::HeaderValue::from_str(&attachment).unwrap(),
-        )
-        .body(Body::from_stream(ReaderStream::new(file)))
-        .unwrap()
+    Ok(())
 }
 
-/// get thumbnails from the data base and return the response
-async fn get_thumbnail(Path(id): Path<i64>) -> impl IntoResponse {
-    let filename = format!("images/{id}_thumb.jpg");
-    let attachment = format!("filename={filename}");
+/// Images uploaded before the size was recorded have a size of 0,
+/// no real image is empty. Read it from the file.
+async fn fill_missing_sizes(pool: &Pool<Sqlite>, storage: &dyn Storage) -> anyhow::Result<()> {
+    let rows = sqlx::query("SELECT id, format FROM images WHERE size = 0")
+        .fetch_all(pool)
+        .await?;
 
-    // create hash map of headers
-    let mut headers = HeaderMap::new();
+    for row in rows {
+        let id = row.get::<i64, _>(0);
+        let key = original_key(id, stored_format(row.get(1)));
+
+        match storage.stream(&key).await? {
+            Some(file) => {
+                sqlx::query("UPDATE images SET size = ? WHERE id = ?")
+                    .bind(file.size as i64)
+                    .bind(id)
+                    .execute(pool)
+                    .await?;
+            }
+            None => eprintln!("can't find {key} to read its size"),
+        }
+    }
 
-    // add content_type header
-    headers.insert(
-        header::CONTENT_TYPE,
-        header::HeaderValue::from_static("image/jpeg"),
-    );
-    // add the content_disposition, ed to convey additional information about how to process the response payload,
-    // and also can be used to attach additional metadata,
-    // such as the filename to use when saving the response payload locally
-    headers.insert(
-        header::CONTENT_DISPOSITION,
-        header::HeaderValue::from_str(&attachment).unwrap(),
-    );
-
-    // get the file name using tokio
-    let file = tokio::fs::File::open(&filename).await.unwrap();
-
-    // build the response body
-    axum::response::Response::builder()
-        .header(
-            header::CONTENT_TYPE,
-            header::HeaderValue::from_static("image/jpeg"),
-        )
-        .header(
-            header::CONTENT_DISPOSITION,
-            header::HeaderValue::from_str(&attachment).unwrap(),
-        )
-        .body(Body::from_stream(ReaderStream::new(file)))
-        .unwrap()
+    Ok(())
 }
 
-/// create a thumbnail from an image id
-fn make_thumbnail(id: i64) -> anyhow::Result<()> {
-    let image_path = format!("images/{id}.jpg");
-    let thumbnail_path = format!("images/{id}_thumb.jpg");
-
-    // read all image into vec of bytes
-    let image_bytes: Vec<u8> = std::fs::read(image_path)?;
+/// check if we are missing thumbnail (or perceptual hash) from existing images,
+/// and queue a job for the workers to create them
+async fn fill_missing_thumbnails(
+    pool: &Pool<Sqlite>,
+    config: &Config,
+    storage: &dyn Storage,
+) -> anyhow::Result<()> {
+    // get all the imagine by id
+    let rows = sqlx::query("SELECT id, perceptual_hash IS NULL FROM images")
+        .fetch_all(pool)
+        .await?;
 
-    let image = if let std::result::Result::Ok(format) = image::guess_format(&image_bytes) {
-        image::load_from_memory_with_format(&image_bytes, format)?
-    } else {
-        image::load_from_memory(&image_bytes)?
-    };
-    let thumbnail = image.thumbnail(100, 100);
-    thumbnail.save(thumbnail_path)?;
+    for row in rows {
+        let id: i64 = row.get(0);
+        // if the thumbnail of any preset is missing we are creating them
+        let mut missing = row.get::<bool, _>(1);
+        for preset in &config.thumbnail_presets {
+            if missing {
+                break;
+            }
+            missing = !storage
+                .exists(&thumbnail_key(id, preset, ImageFormat::Jpeg))
+                .await?;
+        }
+        if !missing {
+            continue;
+        }
+        // the job could only fail, until the original is put back
+        let file = image_file(pool, id)
+            .await
+            .map_err(|e| anyhow::anyhow!("can't find image {id}: {e}"))?;
+        if !storage.exists(&file.key()).await? {
+            eprintln!("can't find {} to make its thumbnails", file.key());
+            continue;
+        }
+        jobs::enqueue_once(pool, JobKind::Thumbnail, id).await?;
+    }
 
     Ok(())
 }
 
-/// check if we are missing thumbnail from existing images
-async fn fill_missing_thumbnails(pool: &Pool<Sqlite>) -> anyhow::Result<()> {
-    // get all the imagine by id
-    let mut rows = sqlx::query("SELECT id FROM images").fetch(pool);
+/// queue a job reading the metadata of the images uploaded before it was recorded
+async fn fill_missing_metadata(pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+    let ids: Vec<i64> = sqlx::query_scalar(
+        "SELECT id FROM images WHERE id NOT IN (SELECT image_id FROM image_metadata)",
+    )
+    .fetch_all(pool)
+    .await?;
 
-    // iterate through all images from the pool
-    while let Some(row) = rows.try_next().await? {
-        // we are only interested bu the first element from the Get response
-        let id = row.get::<i64, _>(0);
+    for id in ids {
+        jobs::enqueue_once(pool, JobKind::Metadata, id).await?;
+    }
 
-        // the the folder path where we store the thumbnails
-        let thumbnail_path = format!("images/{id}_thumb.jpg");
+    Ok(())
+}
 
-        // if the the thumbnails is missing  we are creating it
-        if !std::path::Path::new(&thumbnail_path).exists() {
-            spawn_blocking(move || make_thumbnail(id)).await??;
-        }
+/// in privacy mode, queue a job stripping the metadata of the images
+/// uploaded before it was turned on
+async fn strip_existing_images(pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+    let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM images WHERE metadata_stripped = 0")
+        .fetch_all(pool)
+        .await?;
+
+    for id in ids {
+        jobs::enqueue_once(pool, JobKind::StripMetadata, id).await?;
     }
 
     Ok(())
@@ -253,51 +567,96 @@ async fn fill_missing_thumbnails(pool: &Pool<Sqlite>) -> anyhow::Result<()> {
 // Deserialize and Serialize for JSON format
 // Debug just to be able to print
 #[derive(Deserialize, Serialize, FromRow, Debug)]
-struct ImageRecord {
-    id: i64,
-    tags: String,
-}
-
-/// get all the existing images and display them
-async fn list_images(Extension(pool): Extension<sqlx::SqlitePool>) -> Json<Vec<ImageRecord>> {
-    sqlx::query_as::<_, ImageRecord>("SELECT id, tags FROM images ORDER BY id")
-        .fetch_all(&pool)
-        .await
-        .unwrap()
-        .into() // simply convert the Vec into Json
+pub struct ImageRecord {
+    pub id: i64,
+    // read from the JSON array built by IMAGE_COLUMNS
+    #[sqlx(json)]
+    pub tags: Vec<String>,
+    /// unix timestamp of the upload
+    pub created_at: i64,
+    /// size of the original in bytes
+    pub size: i64,
+    pub title: String,
+    pub description: String,
+    /// dimensions, EXIF..., missing until the metadata of an old image is read
+    #[sqlx(json(nullable))]
+    pub metadata: Option<ImageMetadata>,
+    /// URL of the image, holding its version when it is known so it can be
+    /// cached forever
+    pub image_url: String,
+    /// same for the default thumbnail, the query string also works with the
+    /// other presets
+    pub thumbnail_url: String,
+    /// for a text search, the best matching part of the text with the
+    /// matched words in <mark>, ready to be inserted in HTML
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
 }
 
+/// columns of an `ImageRecord`, used as `SELECT {IMAGE_COLUMNS} FROM images`
+const IMAGE_COLUMNS: &str = "images.id, images.created_at, images.size,
+    images.title, images.description,
+    '/image/' || images.id || IFNULL(
+        '?v=' || images.content_hash || IIF(images.metadata_stripped, '-stripped', ''), ''
+    ) AS image_url,
+    '/thumb/' || images.id || IFNULL(
+        '?v=' || images.content_hash || '-' || images.thumbnails_at, ''
+    ) AS thumbnail_url,
+    (SELECT json_object(
+        'width', width, 'height', height, 'color_type', color_type,
+        'camera', camera, 'captured_at', captured_at, 'orientation', orientation,
+        'latitude', latitude, 'longitude', longitude
+    ) FROM image_metadata WHERE image_metadata.image_id = images.id) AS metadata,
+    (SELECT json_group_array(name) FROM (
+        SELECT tags.name FROM image_tags
+        JOIN tags ON tags.id = image_tags.tag_id
+        WHERE image_tags.image_id = images.id
+        ORDER BY tags.name
+    )) AS tags";
+
+/// query string of `/images`
 #[derive(Deserialize)]
-struct Search {
-    tags: String,
+struct ListImages {
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    sort: Option<Sort>,
+    order: Option<Order>,
 }
 
-/// use tokio form deserialization that attached a type from a query
-async fn search_images(
+/// get the existing images one page at a time, the next page is given
+/// both by `next_cursor` and by a `Link: <...>; rel="next"` header
+async fn list_images(
     Extension(pool): Extension<sqlx::SqlitePool>,
-    Form(form): Form<Search>,
-) -> Html<String> {
-    let tag = format!("%{}%", form.tags);
+    query: Result<Query<ListImages>, QueryRejection>,
+) -> Result<(HeaderMap, Json<ImagePage>), AppError> {
+    let Query(list) = query.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let page = PageRequest::new(list.sort, list.order, list.limit, list.cursor.as_deref())?;
+
+    let filter = ImageFilter {
+        min_width: list.min_width,
+        min_height: list.min_height,
+        ..ImageFilter::default()
+    };
 
-    let rows = sqlx::query_as::<_, ImageRecord>(
-        "SELECT id, tags FROM images WHERE tags LIKE ? ORDER BY id",
-    )
-    .bind(tag)
-    .fetch_all(&pool)
-    .await
-    .unwrap();
+    let images = search::find_images(&pool, &filter, Some(&page)).await?;
+    let page = ImagePage::new(images, &page);
 
-    let mut results = String::new();
-    for row in rows {
-        results.push_str(&format!(
-            "<a href=\"/image/{}\"><img src='/thumb/{}' /></a><br />",
-            row.id, row.id
-        ));
+    // the cursor remembers the sort, it doesn't need to be repeated,
+    // the filters do
+    let mut headers = HeaderMap::new();
+    if let Some(cursor) = &page.next_cursor {
+        let mut link = format!("</images?limit={}&cursor={cursor}", page.limit);
+        if let Some(width) = filter.min_width {
+            link.push_str(&format!("&min_width={width}"));
+        }
+        if let Some(height) = filter.min_height {
+            link.push_str(&format!("&min_height={height}"));
+        }
+        link.push_str(">; rel=\"next\"");
+        headers.insert(header::LINK, link.parse()?);
     }
-
-    let path = std::path::Path::new("src/search.html");
-    let mut content = tokio::fs::read_to_string(path).await.unwrap();
-    content = content.replace("{results}", &results);
-
-    Html(content)
+    Ok((headers, Json(page)))
 }
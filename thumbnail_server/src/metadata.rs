@@ -0,0 +1,214 @@
+//! What we know about an image besides its tags: the dimensions, the color type
+//! and a few EXIF fields (camera, capture time, orientation, GPS position).
+//! It is read once, at upload, and stored in the `image_metadata` table.
+
+use std::io::Cursor;
+
+use axum::{extract::Path, Extension, Json};
+use exif::{Exif, In, Tag, Value};
+use image::{ImageDecoder, ImageReader};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Row, SqliteExecutor};
+
+use crate::error::AppError;
+
+/// A row of `image_metadata`, also part of the JSON of an image
+#[derive(Serialize, Deserialize, FromRow, Debug, Clone, PartialEq)]
+pub struct ImageMetadata {
+    pub width: u32,
+    pub height: u32,
+    /// such as `rgb8`, `rgba8` or `l16`
+    pub color_type: String,
+    /// make and model of the camera
+    pub camera: Option<String>,
+    /// unix timestamp of the capture, a time without offset is taken as UTC
+    pub captured_at: Option<i64>,
+    /// EXIF orientation, 1 (normal) to 8
+    pub orientation: Option<u32>,
+    /// GPS position in degrees, negative in the south and the west
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+/// Read the metadata of an image. Only the header and the EXIF block are read,
+/// an image without (valid) EXIF simply has no camera, capture time...
+pub fn read_metadata(bytes: &[u8]) -> anyhow::Result<ImageMetadata> {
+    let decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    let color_type = format!("{:?}", decoder.color_type()).to_lowercase();
+
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(bytes))
+        .ok();
+    let Some(exif) = exif else {
+        return Ok(ImageMetadata {
+            width,
+            height,
+            color_type,
+            camera: None,
+            captured_at: None,
+            orientation: None,
+            latitude: None,
+            longitude: None,
+        });
+    };
+
+    Ok(ImageMetadata {
+        width,
+        height,
+        color_type,
+        camera: camera(&exif),
+        captured_at: captured_at(&exif),
+        orientation: exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .filter(|orientation| (1..=8).contains(orientation)),
+        latitude: coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S'),
+        longitude: coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W'),
+    })
+}
+
+/// first string of an ASCII field, without the padding
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+    let Value::Ascii(values) = &field.value else {
+        return None;
+    };
+    let text = String::from_utf8_lossy(values.first()?);
+    let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// "Canon Canon EOS 5D" is written "Canon EOS 5D"
+fn camera(exif: &Exif) -> Option<String> {
+    match (ascii(exif, Tag::Make), ascii(exif, Tag::Model)) {
+        (Some(make), Some(model)) if model.starts_with(&make) => Some(model),
+        (Some(make), Some(model)) => Some(format!("{make} {model}")),
+        (make, model) => make.or(model),
+    }
+}
+
+/// when the picture was taken, or at least last modified by the camera
+fn captured_at(exif: &Exif) -> Option<i64> {
+    let (field, offset) = match exif.get_field(Tag::DateTimeOriginal, In::PRIMARY) {
+        Some(field) => (field, Tag::OffsetTimeOriginal),
+        None => (exif.get_field(Tag::DateTime, In::PRIMARY)?, Tag::OffsetTime),
+    };
+    let Value::Ascii(values) = &field.value else {
+        return None;
+    };
+    let mut time = exif::DateTime::from_ascii(values.first()?).ok()?;
+    if let Some(Value::Ascii(values)) = exif.get_field(offset, In::PRIMARY).map(|f| &f.value) {
+        if let Some(value) = values.first() {
+            // a broken offset is ignored, the time is still useful
+            let _ = time.parse_offset(value);
+        }
+    }
+
+    if !(1..=12).contains(&time.month)
+        || !(1..=31).contains(&time.day)
+        || time.hour > 23
+        || time.minute > 59
+        || time.second > 60
+    {
+        return None;
+    }
+    let days = days_from_civil(time.year.into(), time.month.into(), time.day.into());
+    let seconds = days * 86400
+        + i64::from(time.hour) * 3600
+        + i64::from(time.minute) * 60
+        + i64::from(time.second);
+    Some(seconds - i64::from(time.offset.unwrap_or(0)) * 60)
+}
+
+/// number of days between 1970-01-01 and a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // from http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// a GPS coordinate in degrees, from its degrees/minutes/seconds and its reference
+/// (N or S, E or W), `negative` is the reference of the negative values
+fn coordinate(exif: &Exif, tag: Tag, reference: Tag, negative: u8) -> Option<f64> {
+    let Value::Rational(parts) = &exif.get_field(tag, In::PRIMARY)?.value else {
+        return None;
+    };
+    let [degrees, minutes, seconds] = parts.as_slice() else {
+        return None;
+    };
+    let mut value = degrees.to_f64() + minutes.to_f64() / 60.0 + seconds.to_f64() / 3600.0;
+    if !value.is_finite() {
+        return None;
+    }
+    if ascii(exif, reference).is_some_and(|r| r.as_bytes().first() == Some(&negative)) {
+        value = -value;
+    }
+    Some(value)
+}
+
+/// record the metadata of an image, replacing what was known before
+pub async fn save_metadata(
+    executor: impl SqliteExecutor<'_>,
+    image_id: i64,
+    metadata: &ImageMetadata,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO image_metadata
+         (image_id, width, height, color_type, camera, captured_at, orientation, latitude, longitude)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(image_id)
+    .bind(metadata.width)
+    .bind(metadata.height)
+    .bind(&metadata.color_type)
+    .bind(&metadata.camera)
+    .bind(metadata.captured_at)
+    .bind(metadata.orientation)
+    .bind(metadata.latitude)
+    .bind(metadata.longitude)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// JSON of `/image/:id/meta`
+#[derive(Serialize, Debug)]
+pub struct ImageInfo {
+    pub id: i64,
+    pub format: String,
+    /// size of the original in bytes
+    pub size: i64,
+    /// missing until the metadata job of an old image has run
+    pub metadata: Option<ImageMetadata>,
+}
+
+/// everything we know about the file of an image
+pub async fn get_metadata(
+    Path(id): Path<i64>,
+    Extension(pool): Extension<sqlx::SqlitePool>,
+) -> Result<Json<ImageInfo>, AppError> {
+    let row = sqlx::query("SELECT IFNULL(format, 'jpg'), size FROM images WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Image {id} not found")))?;
+    let metadata =
+        sqlx::query_as::<_, ImageMetadata>("SELECT * FROM image_metadata WHERE image_id = ?")
+            .bind(id)
+            .fetch_optional(&pool)
+            .await?;
+
+    Ok(Json(ImageInfo {
+        id,
+        format: row.get(0),
+        size: row.get(1),
+        metadata,
+    }))
+}
@@ -0,0 +1,47 @@
+//! Choosing the format of a thumbnail from the `Accept` header of the request.
+//! WebP and AVIF thumbnails are much smaller than the JPEG ones, they are sent
+//! to the clients that can decode them.
+
+use axum::http::{header, HeaderMap};
+use image::ImageFormat;
+
+/// The formats of a thumbnail a client takes, best first, JPEG last.
+/// A modern format is only sent to the clients naming it: `image/*` and `*/*`
+/// are also sent by browsers that can't decode WebP or AVIF.
+/// Between formats with the same weight, the order of `offered` wins.
+pub fn preferred_formats(request: &HeaderMap, offered: &[ImageFormat]) -> Vec<ImageFormat> {
+    let mut accepted: Vec<(ImageFormat, f32)> = offered
+        .iter()
+        .filter_map(|&format| {
+            let weight = weight(request, format.to_mime_type())?;
+            (weight > 0.0).then_some((format, weight))
+        })
+        .collect();
+    // a stable sort keeps the server preference between equal weights
+    accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut formats: Vec<ImageFormat> = accepted.into_iter().map(|(format, _)| format).collect();
+    formats.push(ImageFormat::Jpeg);
+    formats
+}
+
+/// the `q` the Accept header gives to a media type it names, `None` when it doesn't
+fn weight(request: &HeaderMap, mime_type: &str) -> Option<f32> {
+    request
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            if !parts.next()?.eq_ignore_ascii_case(mime_type) {
+                return None;
+            }
+            let q = parts
+                .filter_map(|parameter| parameter.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok());
+            // a broken weight doesn't make the type acceptable
+            Some(q.unwrap_or(0.0))
+        })
+}
@@ -0,0 +1,450 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite};
+
+use crate::{error::AppError, ImageRecord};
+
+/// number of images in a page when the client doesn't say
+pub const DEFAULT_LIMIT: i64 = 50;
+/// largest page a client can ask for
+pub const MAX_LIMIT: i64 = 200;
+
+/// Column a list of images is sorted by. The id always breaks ties,
+/// so the order is stable even when many images share the same value.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    #[default]
+    Id,
+    CreatedAt,
+    Size,
+    /// when the picture was taken, the upload time for the images without it
+    CapturedAt,
+    /// best full-text matches first, only for a text search. It has no order.
+    /// The score is not stable enough for a key, these pages use an offset.
+    Relevance,
+}
+
+/// bm25 score of a full-text match, lower is better.
+/// A match in the title counts more than in the tags, more than in the description.
+pub const RANK: &str = "bm25(images_fts, 10.0, 2.0, 5.0)";
+
+impl Sort {
+    fn column(self) -> &'static str {
+        match self {
+            Sort::Id => "images.id",
+            Sort::CreatedAt => "images.created_at",
+            Sort::Size => "images.size",
+            Sort::CapturedAt => {
+                "IFNULL((SELECT captured_at FROM image_metadata
+                         WHERE image_metadata.image_id = images.id), images.created_at)"
+            }
+            Sort::Relevance => RANK,
+        }
+    }
+
+    /// the value of the sort column for an image
+    fn key(self, image: &ImageRecord) -> i64 {
+        match self {
+            Sort::Id | Sort::Relevance => image.id,
+            Sort::CreatedAt => image.created_at,
+            Sort::Size => image.size,
+            Sort::CapturedAt => image
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.captured_at)
+                .unwrap_or(image.created_at),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Where the next page starts. Clients only see it as an opaque string,
+/// so its content can change without breaking them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    /// the cursor is only valid for the sort it was created for
+    pub sort: Sort,
+    pub order: Order,
+    /// sort column and id of the last image of the previous page,
+    /// for `Sort::Relevance` the key is the number of images already seen
+    pub key: i64,
+    pub id: i64,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("a cursor is always serializable");
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    pub fn decode(text: &str) -> Result<Self, AppError> {
+        URL_SAFE_NO_PAD
+            .decode(text)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| AppError::BadRequest("Invalid cursor".to_string()))
+    }
+}
+
+/// What a client asked for: the page size, the order, and where to start
+#[derive(Debug, Clone)]
+pub struct PageRequest {
+    pub sort: Sort,
+    pub order: Order,
+    pub limit: i64,
+    pub cursor: Option<Cursor>,
+}
+
+impl PageRequest {
+    /// check the parameters sent by a client
+    pub fn new(
+        sort: Option<Sort>,
+        order: Option<Order>,
+        limit: Option<i64>,
+        cursor: Option<&str>,
+    ) -> Result<Self, AppError> {
+        let limit = match limit {
+            None => DEFAULT_LIMIT,
+            Some(limit) if (1..=MAX_LIMIT).contains(&limit) => limit,
+            Some(_) => {
+                return Err(AppError::BadRequest(format!(
+                    "limit must be between 1 and {MAX_LIMIT}"
+                )))
+            }
+        };
+
+        let cursor = cursor.map(Cursor::decode).transpose()?;
+        // a cursor carries its sort, the client doesn't have to repeat it
+        let sort = sort.or(cursor.as_ref().map(|c| c.sort)).unwrap_or_default();
+        if sort == Sort::Relevance && order.is_some() {
+            return Err(AppError::BadRequest(
+                "order can't be used with sort=relevance, the default of a text search: \
+                 the best matches always come first"
+                    .to_string(),
+            ));
+        }
+        let order = order
+            .or(cursor.as_ref().map(|c| c.order))
+            .unwrap_or_default();
+        if let Some(cursor) = &cursor {
+            if cursor.sort != sort || cursor.order != order {
+                return Err(AppError::BadRequest(
+                    "The cursor was created for another sort order".to_string(),
+                ));
+            }
+        }
+
+        Ok(PageRequest {
+            sort,
+            order,
+            limit,
+            cursor,
+        })
+    }
+
+    /// append the condition skipping the previous pages, if any
+    pub fn push_after(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        let Some(cursor) = &self.cursor else {
+            return;
+        };
+        if self.sort == Sort::Relevance {
+            // skipped by the OFFSET of push_order_and_limit
+            return;
+        }
+        let op = match self.order {
+            Order::Asc => ">",
+            Order::Desc => "<",
+        };
+
+        if self.sort == Sort::Id {
+            query
+                .push(format!(" AND images.id {op} "))
+                .push_bind(cursor.id);
+        } else {
+            let column = self.sort.column();
+            query
+                .push(format!(" AND ({column} {op} "))
+                .push_bind(cursor.key)
+                .push(format!(" OR ({column} = "))
+                .push_bind(cursor.key)
+                .push(format!(" AND images.id {op} "))
+                .push_bind(cursor.id)
+                .push("))");
+        }
+    }
+
+    /// append the ORDER BY and LIMIT clauses. One more image than the page size
+    /// is fetched, to know if there is a next page.
+    pub fn push_order_and_limit(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        let direction = match self.order {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        };
+        if self.sort == Sort::Relevance {
+            // the best match first, `PageRequest::new` refuses an order
+            let offset = self.cursor.as_ref().map_or(0, |c| c.key);
+            query
+                .push(format!(" ORDER BY {RANK}, images.id LIMIT "))
+                .push_bind(self.limit + 1)
+                .push(" OFFSET ")
+                .push_bind(offset);
+            return;
+        }
+
+        if self.sort == Sort::Id {
+            query.push(format!(" ORDER BY images.id {direction}"));
+        } else {
+            let column = self.sort.column();
+            query.push(format!(
+                " ORDER BY {column} {direction}, images.id {direction}"
+            ));
+        }
+        query.push(" LIMIT ").push_bind(self.limit + 1);
+    }
+}
+
+/// One page of images, `next_cursor` is missing on the last page
+#[derive(Serialize, Debug)]
+pub struct ImagePage {
+    pub images: Vec<ImageRecord>,
+    pub next_cursor: Option<String>,
+    pub limit: i64,
+}
+
+impl ImagePage {
+    /// Build a page from up to `limit + 1` images: the extra one only tells
+    /// that there is a next page, it is not returned.
+    pub fn new(mut images: Vec<ImageRecord>, request: &PageRequest) -> Self {
+        let next_cursor = if images.len() as i64 > request.limit {
+            images.truncate(request.limit as usize);
+            images.last().map(|last| {
+                let key = if request.sort == Sort::Relevance {
+                    request.cursor.as_ref().map_or(0, |c| c.key) + request.limit
+                } else {
+                    request.sort.key(last)
+                };
+                Cursor {
+                    sort: request.sort,
+                    order: request.order,
+                    key,
+                    id: last.id,
+                }
+                .encode()
+            })
+        } else {
+            None
+        };
+        ImagePage {
+            images,
+            next_cursor,
+            limit: request.limit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    use crate::test_util::test_pool;
+
+    fn cursor(sort: Sort, order: Order, key: i64, id: i64) -> Cursor {
+        Cursor {
+            sort,
+            order,
+            key,
+            id,
+        }
+    }
+
+    fn request(sort: Sort, order: Order, cursor: Option<Cursor>) -> PageRequest {
+        let cursor = cursor.map(|cursor| cursor.encode());
+        PageRequest::new(Some(sort), Some(order), Some(2), cursor.as_deref()).unwrap()
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let cursor = cursor(Sort::CapturedAt, Order::Desc, -1_700_000_000, 42);
+        let text = cursor.encode();
+        // safe in a query string as it is
+        assert!(text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(Cursor::decode(&text).unwrap(), cursor);
+    }
+
+    #[test]
+    fn broken_cursors_are_refused() {
+        for text in [
+            "",
+            "not base64!",
+            "bm90IGpzb24",
+            &URL_SAFE_NO_PAD.encode("{}"),
+        ] {
+            assert!(matches!(
+                Cursor::decode(text),
+                Err(AppError::BadRequest(message)) if message == "Invalid cursor"
+            ));
+        }
+    }
+
+    #[test]
+    fn limit_is_checked() {
+        let limit = |limit| PageRequest::new(None, None, limit, None).map(|r| r.limit);
+        assert_eq!(limit(None).unwrap(), DEFAULT_LIMIT);
+        assert_eq!(limit(Some(1)).unwrap(), 1);
+        assert_eq!(limit(Some(MAX_LIMIT)).unwrap(), MAX_LIMIT);
+        assert!(limit(Some(0)).is_err());
+        assert!(limit(Some(MAX_LIMIT + 1)).is_err());
+    }
+
+    #[test]
+    fn cursor_gives_its_sort() {
+        let text = cursor(Sort::Size, Order::Desc, 10, 3).encode();
+        let request = PageRequest::new(None, None, None, Some(&text)).unwrap();
+        assert_eq!((request.sort, request.order), (Sort::Size, Order::Desc));
+        let request = PageRequest::new(Some(Sort::Size), None, None, Some(&text)).unwrap();
+        assert_eq!(request.order, Order::Desc);
+    }
+
+    #[test]
+    fn relevance_has_no_order() {
+        for order in [Order::Asc, Order::Desc] {
+            let error =
+                PageRequest::new(Some(Sort::Relevance), Some(order), None, None).unwrap_err();
+            assert!(matches!(
+                error,
+                AppError::BadRequest(message) if message.contains("relevance")
+            ));
+        }
+        let text = cursor(Sort::Relevance, Order::Asc, 50, 0).encode();
+        let error = PageRequest::new(None, Some(Order::Desc), None, Some(&text)).unwrap_err();
+        assert!(matches!(error, AppError::BadRequest(_)));
+
+        let request = PageRequest::new(None, None, None, Some(&text)).unwrap();
+        assert_eq!(request.sort, Sort::Relevance);
+    }
+
+    #[test]
+    fn cursor_of_another_sort_is_refused() {
+        let text = cursor(Sort::Size, Order::Desc, 10, 3).encode();
+        for (sort, order) in [
+            (Some(Sort::CreatedAt), None),
+            (None, Some(Order::Asc)),
+            (Some(Sort::Size), Some(Order::Asc)),
+        ] {
+            let error = PageRequest::new(sort, order, None, Some(&text)).unwrap_err();
+            assert!(matches!(
+                error,
+                AppError::BadRequest(message) if message.contains("another sort order")
+            ));
+        }
+    }
+
+    #[test]
+    fn keyset_condition_breaks_ties_with_the_id() {
+        let sql = |request: &PageRequest| {
+            let mut query = QueryBuilder::<Sqlite>::new("");
+            request.push_after(&mut query);
+            query.sql().split_whitespace().collect::<Vec<_>>().join(" ")
+        };
+        assert_eq!(sql(&request(Sort::Size, Order::Asc, None)), "");
+        assert_eq!(
+            sql(&request(
+                Sort::Size,
+                Order::Asc,
+                Some(cursor(Sort::Size, Order::Asc, 10, 3))
+            )),
+            "AND (images.size > ? OR (images.size = ? AND images.id > ?))"
+        );
+        assert_eq!(
+            sql(&request(
+                Sort::CreatedAt,
+                Order::Desc,
+                Some(cursor(Sort::CreatedAt, Order::Desc, 10, 3))
+            )),
+            "AND (images.created_at < ? OR (images.created_at = ? AND images.id < ?))"
+        );
+        assert_eq!(
+            sql(&request(
+                Sort::Id,
+                Order::Desc,
+                Some(cursor(Sort::Id, Order::Desc, 3, 3))
+            )),
+            "AND images.id < ?"
+        );
+    }
+
+    /// images whose created_at, size and capture time have many ties
+    async fn database() -> SqlitePool {
+        let pool = test_pool().await;
+        sqlx::raw_sql(
+            "INSERT INTO images (id, created_at, size) VALUES
+                 (1, 100, 500), (2, 100, 300), (3, 200, 500), (4, 100, 500),
+                 (5, 300, 300), (6, 200, 100), (7, 200, 500);
+             INSERT INTO image_metadata (image_id, width, height, color_type, captured_at)
+             VALUES (1, 1, 1, 'rgb8', 200), (3, 1, 1, 'rgb8', 50), (5, 1, 1, 'rgb8', NULL),
+                    (6, 1, 1, 'rgb8', 200);",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool
+    }
+
+    /// every id, read a page of two at a time
+    async fn all_pages(pool: &SqlitePool, sort: Sort, order: Order) -> Vec<i64> {
+        let mut ids = Vec::new();
+        let mut next = None;
+        loop {
+            let request = request(sort, order, next.take());
+            let mut query = QueryBuilder::<Sqlite>::new(format!(
+                "SELECT images.id, {} FROM images WHERE 1 = 1",
+                sort.column()
+            ));
+            request.push_after(&mut query);
+            request.push_order_and_limit(&mut query);
+            let mut rows: Vec<(i64, i64)> = query.build_query_as().fetch_all(pool).await.unwrap();
+
+            let more = rows.len() as i64 > request.limit;
+            rows.truncate(request.limit as usize);
+            ids.extend(rows.iter().map(|(id, _)| id));
+            match rows.last() {
+                Some(&(id, key)) if more => next = Some(cursor(sort, order, key, id)),
+                _ => return ids,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_follow_each_other_through_ties() {
+        let pool = database().await;
+        let cases = [
+            (Sort::Id, Order::Asc, [1, 2, 3, 4, 5, 6, 7]),
+            (Sort::Id, Order::Desc, [7, 6, 5, 4, 3, 2, 1]),
+            (Sort::CreatedAt, Order::Asc, [1, 2, 4, 3, 6, 7, 5]),
+            (Sort::CreatedAt, Order::Desc, [5, 7, 6, 3, 4, 2, 1]),
+            (Sort::Size, Order::Asc, [6, 2, 5, 1, 3, 4, 7]),
+            (Sort::Size, Order::Desc, [7, 4, 3, 1, 5, 2, 6]),
+            // without a capture time, the upload time
+            (Sort::CapturedAt, Order::Asc, [3, 2, 4, 1, 6, 7, 5]),
+            (Sort::CapturedAt, Order::Desc, [5, 7, 6, 1, 4, 2, 3]),
+        ];
+        for (sort, order, expected) in cases {
+            assert_eq!(
+                all_pages(&pool, sort, order).await,
+                expected,
+                "{sort:?} {order:?}"
+            );
+        }
+    }
+}
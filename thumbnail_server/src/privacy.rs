@@ -0,0 +1,699 @@
+//! Privacy mode (`STRIP_METADATA=true`): phones write the GPS position, the
+//! camera serial number... into their photos. The served copy of an image has
+//! its EXIF, XMP and IPTC metadata removed, the untouched original is kept in
+//! `images/private` where only the admins can get it.
+//!
+//! JPEG, PNG, GIF and WebP are cleaned without touching the pixels, the other
+//! formats are decoded and encoded again. The camera and the capture time are
+//! not recorded for a stripped image either, `/images` would give them away.
+
+use anyhow::Context;
+use image::ImageFormat;
+use tokio::task::spawn_blocking;
+
+use crate::{
+    files::ImageFile,
+    imaging::{encode_image, load_image, DecodeBudget},
+    metadata::{read_metadata, ImageMetadata},
+    storage::Storage,
+};
+
+/// folder of the untouched originals
+pub const PRIVATE_DIR: &str = "private";
+
+/// Make the copy of an image that is safe to serve. The orientation is part of
+/// the removed EXIF, so a JPEG that is not stored the right way up gets an
+/// EXIF holding only its orientation, the other formats are turned before
+/// being encoded again.
+pub fn strip_metadata(
+    bytes: &[u8],
+    format: ImageFormat,
+    orientation: Option<u32>,
+) -> anyhow::Result<Vec<u8>> {
+    let rotated = orientation.filter(|&orientation| orientation != 1);
+    let rotated = rotated.and_then(|orientation| u16::try_from(orientation).ok());
+    match (format, rotated) {
+        // encoding a JPEG again would lose quality
+        (ImageFormat::Jpeg, Some(orientation)) => {
+            let stripped = strip_jpeg(bytes)?;
+            let exif = orientation_exif(orientation);
+            Ok([&stripped[..2], &exif, &stripped[2..]].concat())
+        }
+        (ImageFormat::Jpeg, None) => strip_jpeg(bytes),
+        (ImageFormat::Png, None) => strip_png(bytes),
+        // a turned WebP is encoded again, losslessly
+        (ImageFormat::WebP, None) => strip_webp(bytes),
+        // no EXIF in a GIF, so never turned: encoding an animated GIF
+        // again would keep only its first frame
+        (ImageFormat::Gif, _) => strip_gif(bytes),
+        // BMP has no metadata
+        (ImageFormat::Bmp, _) => Ok(bytes.to_vec()),
+        // our encoders don't write any metadata
+        _ => encode_image(&load_image(bytes)?, format),
+    }
+}
+
+/// The APP1 segment of an EXIF block whose only field is the orientation
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08".to_vec();
+    // one field: Orientation, a SHORT, padded to 4 bytes
+    exif.extend_from_slice(&1u16.to_be_bytes());
+    exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1]);
+    exif.extend_from_slice(&orientation.to_be_bytes());
+    exif.extend_from_slice(&[0, 0]);
+    // no next IFD
+    exif.extend_from_slice(&[0, 0, 0, 0]);
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+    segment.extend_from_slice(&exif);
+    segment
+}
+
+/// The metadata of a served copy: only what the copy itself tells (dimensions,
+/// orientation...). The camera and the capture time of the original are left
+/// out with its location.
+pub fn public_metadata(public: &[u8]) -> anyhow::Result<ImageMetadata> {
+    Ok(ImageMetadata {
+        camera: None,
+        captured_at: None,
+        latitude: None,
+        longitude: None,
+        ..read_metadata(public)?
+    })
+}
+
+/// Keep the original of an image that was served as it was uploaded, and
+/// replace the served copy with a stripped one. Safe to run again after a crash:
+/// the private copy, once written, is the original.
+/// Returns the size and the metadata of the served copy.
+pub async fn strip_stored_image(
+    storage: &dyn Storage,
+    budget: &DecodeBudget,
+    file: &ImageFile,
+) -> anyhow::Result<(u64, ImageMetadata)> {
+    let private = file.private_key();
+    let bytes = match storage.get(&private).await? {
+        Some(bytes) => bytes,
+        None => {
+            let bytes = storage
+                .get(&file.key())
+                .await?
+                .with_context(|| format!("{} not found", file.key()))?;
+            storage.put(&private, bytes.clone()).await?;
+            bytes
+        }
+    };
+
+    let format = file.format;
+    let decoding = budget.reserve_for(&bytes).await;
+    let (public, metadata) = spawn_blocking(move || {
+        let _decoding = decoding;
+        let orientation = read_metadata(&bytes)?.orientation;
+        let public = strip_metadata(&bytes, format, orientation)?;
+        let metadata = public_metadata(&public)?;
+        Ok::<_, anyhow::Error>((public, metadata))
+    })
+    .await??;
+    let size = public.len() as u64;
+    storage.put(&file.key(), public).await?;
+    Ok((size, metadata))
+}
+
+/// Read the metadata of a stored image, only the public one when it has
+/// a private original.
+pub async fn read_stored_metadata(
+    storage: &dyn Storage,
+    file: &ImageFile,
+) -> anyhow::Result<ImageMetadata> {
+    let public = storage
+        .get(&file.key())
+        .await?
+        .with_context(|| format!("{} not found", file.key()))?;
+    let stripped = storage.exists(&file.private_key()).await?;
+    spawn_blocking(move || {
+        if stripped {
+            public_metadata(&public)
+        } else {
+            read_metadata(&public)
+        }
+    })
+    .await?
+}
+
+/// Remove the APP1 (EXIF, XMP), APP13 (IPTC), comment and non ICC APP2
+/// (multi-picture) segments of a JPEG, and whatever follows its end
+/// (phones append more pictures there). The compressed data is copied as it is.
+fn strip_jpeg(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        anyhow::bail!("not a JPEG file");
+    }
+    let mut output = Vec::with_capacity(bytes.len());
+    output.extend_from_slice(&bytes[..2]);
+
+    let mut position = 2;
+    loop {
+        // a marker may be preceded by fill bytes
+        while bytes.get(position) == Some(&0xFF) && bytes.get(position + 1) == Some(&0xFF) {
+            position += 1;
+        }
+        let (Some(&0xFF), Some(&marker)) = (bytes.get(position), bytes.get(position + 1)) else {
+            anyhow::bail!("truncated JPEG file");
+        };
+
+        match marker {
+            // end of image, anything after it is dropped
+            0xD9 => {
+                output.extend_from_slice(&[0xFF, 0xD9]);
+                return Ok(output);
+            }
+            // markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                output.extend_from_slice(&bytes[position..position + 2]);
+                position += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let length = match bytes.get(position + 2..position + 4) {
+            Some(length) => u16::from_be_bytes([length[0], length[1]]) as usize,
+            None => anyhow::bail!("truncated JPEG file"),
+        };
+        let end = position + 2 + length;
+        if length < 2 || end > bytes.len() {
+            anyhow::bail!("truncated JPEG file");
+        }
+        let payload = &bytes[position + 4..end];
+
+        let keep = match marker {
+            0xE1 | 0xED | 0xFE => false,
+            0xE2 => payload.starts_with(b"ICC_PROFILE\0"),
+            _ => true,
+        };
+        if keep {
+            output.extend_from_slice(&bytes[position..end]);
+        }
+        position = end;
+
+        // start of scan: the compressed data runs until the next marker
+        // that is not a stuffed byte (FF00) or a restart marker
+        if marker == 0xDA {
+            let start = position;
+            while position + 1 < bytes.len() {
+                if bytes[position] == 0xFF
+                    && !matches!(bytes[position + 1], 0x00 | 0xFF | 0xD0..=0xD7)
+                {
+                    break;
+                }
+                position += 1;
+            }
+            output.extend_from_slice(&bytes[start..position]);
+        }
+    }
+}
+
+/// Remove the eXIf, text (XMP lives in iTXt) and time chunks of a PNG
+fn strip_png(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !bytes.starts_with(SIGNATURE) {
+        anyhow::bail!("not a PNG file");
+    }
+    let mut output = Vec::with_capacity(bytes.len());
+    output.extend_from_slice(SIGNATURE);
+
+    let mut position = SIGNATURE.len();
+    while position < bytes.len() {
+        let Some(header) = bytes.get(position..position + 8) else {
+            anyhow::bail!("truncated PNG file");
+        };
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = &header[4..8];
+        // length, type, data and CRC
+        let end = position + 12 + length;
+        if end > bytes.len() {
+            anyhow::bail!("truncated PNG file");
+        }
+
+        if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME") {
+            output.extend_from_slice(&bytes[position..end]);
+        }
+        position = end;
+        if kind == b"IEND" {
+            break;
+        }
+    }
+    Ok(output)
+}
+
+/// Remove the EXIF and XMP chunks of a WebP, and clear their flags in the
+/// VP8X header. The image data is copied as it is, a lossy WebP stays lossy.
+fn strip_webp(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if bytes.get(..4) != Some(b"RIFF") || bytes.get(8..12) != Some(b"WEBP") {
+        anyhow::bail!("not a WebP file");
+    }
+    // anything after the RIFF container is dropped
+    let size = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+    let Some(bytes) = bytes.get(..8 + size) else {
+        anyhow::bail!("truncated WebP file");
+    };
+    let mut output = Vec::with_capacity(bytes.len());
+    output.extend_from_slice(&bytes[..12]);
+
+    let mut position = 12;
+    while position < bytes.len() {
+        let Some(header) = bytes.get(position..position + 8) else {
+            anyhow::bail!("truncated WebP file");
+        };
+        let kind = &header[..4];
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        // type, length and data, padded to an even length
+        let end = position + 8 + length + length % 2;
+        if end > bytes.len() {
+            anyhow::bail!("truncated WebP file");
+        }
+
+        match kind {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let start = output.len();
+                output.extend_from_slice(&bytes[position..end]);
+                // the flags telling there is EXIF and XMP
+                if let Some(flags) = output.get_mut(start + 8) {
+                    *flags &= !0x0C;
+                }
+            }
+            _ => output.extend_from_slice(&bytes[position..end]),
+        }
+        position = end;
+    }
+
+    let size = u32::try_from(output.len() - 8)?;
+    output[4..8].copy_from_slice(&size.to_le_bytes());
+    Ok(output)
+}
+
+/// Remove the comment and application extensions of a GIF (XMP is stored in
+/// one), except the ones telling how often an animation loops and its ICC
+/// profile, and whatever follows its trailer. The frames are copied as they are.
+fn strip_gif(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    /// application extensions that are not metadata
+    const KEPT_APPLICATIONS: [&[u8]; 3] = [b"NETSCAPE2.0", b"ANIMEXTS1.0", b"ICCRGBG1012"];
+
+    if !(bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a")) {
+        anyhow::bail!("not a GIF file");
+    }
+    // header and logical screen descriptor, then the global color table
+    let Some(&flags) = bytes.get(10) else {
+        anyhow::bail!("truncated GIF file");
+    };
+    let mut position = 13 + color_table_size(flags);
+    let mut output = Vec::with_capacity(bytes.len());
+    output.extend_from_slice(bytes.get(..position).context("truncated GIF file")?);
+
+    loop {
+        let start = position;
+        let keep = match bytes.get(position) {
+            // trailer, anything after it is dropped
+            Some(0x3B) => {
+                output.push(0x3B);
+                return Ok(output);
+            }
+            // image descriptor and its local color table, then the minimum
+            // code size before the data
+            Some(0x2C) => {
+                let &flags = bytes.get(position + 9).context("truncated GIF file")?;
+                position += 10 + color_table_size(flags) + 1;
+                true
+            }
+            Some(0x21) => {
+                let &label = bytes.get(position + 1).context("truncated GIF file")?;
+                position += 2;
+                match label {
+                    0xFE => false,
+                    // the first sub-block is the name of the application
+                    0xFF => bytes
+                        .get(position + 1..position + 12)
+                        .is_some_and(|name| KEPT_APPLICATIONS.contains(&name)),
+                    _ => true,
+                }
+            }
+            Some(other) => anyhow::bail!("unknown GIF block {other:#04x}"),
+            None => anyhow::bail!("truncated GIF file"),
+        };
+        // sub-blocks, each one starting with its length, until an empty one
+        loop {
+            let &length = bytes.get(position).context("truncated GIF file")?;
+            position += 1 + length as usize;
+            if length == 0 {
+                break;
+            }
+        }
+        if position > bytes.len() {
+            anyhow::bail!("truncated GIF file");
+        }
+        if keep {
+            output.extend_from_slice(&bytes[start..position]);
+        }
+    }
+}
+
+/// size in bytes of the color table announced by the flags of a GIF descriptor
+fn color_table_size(flags: u8) -> usize {
+    if flags & 0x80 == 0 {
+        0
+    } else {
+        3 << ((flags & 0x07) + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{
+        codecs::gif::{GifDecoder, GifEncoder, Repeat},
+        DynamicImage, Frame, RgbaImage,
+    };
+
+    /// an EXIF block with an empty IFD, enough for the decoders
+    const TIFF: &[u8] = b"MM\0\x2a\0\0\0\x08\0\0\0\0\0\0";
+    const XMP: &[u8] = b"<x:xmpmeta xmlns:x='adobe:ns:meta/'>secret place</x:xmpmeta>";
+
+    fn picture() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 8, |x, y| {
+            image::Rgba([(x * 16) as u8, (y * 32) as u8, 128, 255 - x as u8])
+        }))
+    }
+
+    fn contains(bytes: &[u8], needle: &[u8]) -> bool {
+        bytes.windows(needle.len()).any(|window| window == needle)
+    }
+
+    /// a JPEG segment with its marker and length
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    /// insert bytes into a file, at a given position
+    fn insert(bytes: &[u8], position: usize, inserted: &[Vec<u8>]) -> Vec<u8> {
+        let mut output = bytes[..position].to_vec();
+        for part in inserted {
+            output.extend_from_slice(part);
+        }
+        output.extend_from_slice(&bytes[position..]);
+        output
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in bytes {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    /// a PNG chunk with its length and CRC
+    fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+        chunk
+    }
+
+    /// zlib data of one stored block, as iCCP wants its profile
+    fn zlib_stored(data: &[u8]) -> Vec<u8> {
+        let mut zlib = vec![0x78, 0x01, 0x01];
+        zlib.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(data.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(data);
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in data {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+        zlib
+    }
+
+    fn same_pixels(left: &[u8], right: &[u8]) {
+        let left = load_image(left).unwrap().to_rgba8();
+        let right = load_image(right).unwrap().to_rgba8();
+        assert_eq!(left.dimensions(), right.dimensions());
+        assert!(left == right, "the pixels changed");
+    }
+
+    #[test]
+    fn jpeg_loses_exif_xmp_iptc_and_comments() {
+        let plain = encode_image(&picture(), ImageFormat::Jpeg).unwrap();
+        let mut exif = b"Exif\0\0".to_vec();
+        exif.extend_from_slice(TIFF);
+        let mut xmp = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+        xmp.extend_from_slice(XMP);
+        let mut icc = b"ICC_PROFILE\0\x01\x01".to_vec();
+        icc.extend_from_slice(b"fake profile");
+        let original = insert(
+            &plain,
+            2,
+            &[
+                segment(0xE1, &exif),
+                segment(0xE1, &xmp),
+                segment(0xE2, &icc),
+                segment(0xE2, b"MPF\0more pictures"),
+                segment(0xED, b"Photoshop 3.0\0iptc"),
+                segment(0xFE, b"a comment"),
+            ],
+        );
+        // phones append more pictures after the end
+        let original = [original, b"trailing picture".to_vec()].concat();
+
+        let stripped = strip_metadata(&original, ImageFormat::Jpeg, None).unwrap();
+        assert!(!contains(&stripped, b"Exif\0\0"));
+        assert!(!contains(&stripped, b"secret place"));
+        assert!(!contains(&stripped, b"MPF\0"));
+        assert!(!contains(&stripped, b"Photoshop"));
+        assert!(!contains(&stripped, b"a comment"));
+        assert!(!contains(&stripped, b"trailing picture"));
+        assert!(contains(&stripped, b"ICC_PROFILE\0"));
+        assert!(stripped.ends_with(&[0xFF, 0xD9]));
+        // only the segments are gone, the rest is copied as it is
+        assert_eq!(stripped, insert(&plain, 2, &[segment(0xE2, &icc)]),);
+        same_pixels(&stripped, &plain);
+    }
+
+    #[test]
+    fn turned_jpeg_keeps_its_pixels_and_orientation() {
+        let plain = encode_image(&picture(), ImageFormat::Jpeg).unwrap();
+        // Make, then Orientation 6, then the text of Make
+        let mut tiff = b"MM\0\x2a\0\0\0\x08\0\x02".to_vec();
+        tiff.extend_from_slice(&[0x01, 0x0F, 0, 2, 0, 0, 0, 14, 0, 0, 0, 38]);
+        tiff.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(b"Secret Camera\0");
+        let exif = [b"Exif\0\0", &tiff[..]].concat();
+        let original = insert(&plain, 2, &[segment(0xE1, &exif)]);
+        let metadata = read_metadata(&original).unwrap();
+        assert_eq!(metadata.camera.as_deref(), Some("Secret Camera"));
+        assert_eq!(metadata.orientation, Some(6));
+
+        let stripped = strip_metadata(&original, ImageFormat::Jpeg, Some(6)).unwrap();
+        assert!(!contains(&stripped, b"Secret Camera"));
+        // the compressed data is copied as it is
+        assert_eq!(stripped, insert(&plain, 2, &[orientation_exif(6)]));
+        // still shown the right way up
+        let turned = load_image(&stripped).unwrap();
+        assert_eq!((turned.width(), turned.height()), (8, 16));
+        let metadata = public_metadata(&stripped).unwrap();
+        assert_eq!(metadata.orientation, Some(6));
+        assert_eq!(metadata.camera, None);
+        assert_eq!(metadata.captured_at, None);
+    }
+
+    #[test]
+    fn truncated_jpeg_is_refused() {
+        let plain = encode_image(&picture(), ImageFormat::Jpeg).unwrap();
+        assert!(strip_jpeg(&plain[..plain.len() / 2]).is_err());
+        assert!(strip_jpeg(b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn png_loses_exif_and_text_chunks() {
+        let plain = encode_image(&picture(), ImageFormat::Png).unwrap();
+        let mut itxt = b"XML:com.adobe.xmp\0\0\0\0\0".to_vec();
+        itxt.extend_from_slice(XMP);
+        let mut iccp = b"fake\0\0".to_vec();
+        iccp.extend_from_slice(&zlib_stored(b"fake profile"));
+        let icc = chunk(b"iCCP", &iccp);
+        // after the signature and IHDR
+        let original = insert(
+            &plain,
+            33,
+            &[
+                icc.clone(),
+                chunk(b"eXIf", TIFF),
+                chunk(b"tEXt", b"Comment\0a comment"),
+                chunk(b"iTXt", &itxt),
+                chunk(b"tIME", &[7, 232, 10, 15, 12, 0, 0]),
+            ],
+        );
+        let original = [original, b"trailing data".to_vec()].concat();
+
+        let stripped = strip_metadata(&original, ImageFormat::Png, None).unwrap();
+        for kind in [b"eXIf", b"tEXt", b"iTXt", b"tIME"] {
+            assert!(!contains(&stripped, kind));
+        }
+        assert!(!contains(&stripped, b"secret place"));
+        assert!(!contains(&stripped, b"trailing data"));
+        assert_eq!(stripped, insert(&plain, 33, &[icc]));
+        same_pixels(&stripped, &plain);
+    }
+
+    #[test]
+    fn truncated_png_is_refused() {
+        let plain = encode_image(&picture(), ImageFormat::Png).unwrap();
+        assert!(strip_png(&plain[..plain.len() - 6]).is_err());
+        assert!(strip_png(b"\xFF\xD8\xFF").is_err());
+    }
+
+    /// the frames of a GIF, decoded
+    fn frames(bytes: &[u8]) -> Vec<RgbaImage> {
+        use image::AnimationDecoder;
+        let decoder = GifDecoder::new(std::io::Cursor::new(bytes)).unwrap();
+        decoder
+            .into_frames()
+            .map(|frame| frame.unwrap().into_buffer())
+            .collect()
+    }
+
+    /// a GIF extension, its data cut into sub-blocks
+    fn extension(label: u8, first: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut extension = vec![0x21, label];
+        for block in [first].into_iter().chain(data.chunks(255)) {
+            if !block.is_empty() {
+                extension.push(block.len() as u8);
+                extension.extend_from_slice(block);
+            }
+        }
+        extension.push(0);
+        extension
+    }
+
+    #[test]
+    fn gif_loses_comments_and_xmp() {
+        let mut plain = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut plain);
+            encoder.set_repeat(Repeat::Infinite).unwrap();
+            for shift in [0, 64] {
+                let image = RgbaImage::from_fn(16, 8, |x, y| {
+                    image::Rgba([(x * 8) as u8 + shift, (y * 32) as u8, 0, 255])
+                });
+                encoder.encode_frame(Frame::new(image)).unwrap();
+            }
+        }
+        let long_xmp = XMP.repeat(10);
+        let icc = extension(0xFF, b"ICCRGBG1012", b"fake profile");
+        let position = 13 + color_table_size(plain[10]);
+        let original = insert(
+            &plain,
+            position,
+            &[
+                extension(0xFE, b"", b"a comment"),
+                extension(0xFF, b"XMP DataXMP", &long_xmp),
+                icc.clone(),
+            ],
+        );
+        let original = [original, b"trailing data".to_vec()].concat();
+
+        let stripped = strip_metadata(&original, ImageFormat::Gif, None).unwrap();
+        assert!(!contains(&stripped, b"a comment"));
+        assert!(!contains(&stripped, b"XMP DataXMP"));
+        assert!(!contains(&stripped, b"secret place"));
+        assert!(!contains(&stripped, b"trailing data"));
+        // still an endless animation
+        assert!(contains(&stripped, b"NETSCAPE2.0"));
+        assert_eq!(stripped, insert(&plain, position, &[icc]));
+
+        let stripped_frames = frames(&stripped);
+        assert_eq!(stripped_frames.len(), 2);
+        assert_eq!(stripped_frames, frames(&plain));
+    }
+
+    #[test]
+    fn truncated_gif_is_refused() {
+        let plain = encode_image(&picture(), ImageFormat::Gif).unwrap();
+        assert!(strip_gif(&plain[..plain.len() - 4]).is_err());
+        assert!(strip_gif(b"GIF89a").is_err());
+    }
+
+    /// a RIFF chunk, padded to an even length
+    fn riff_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    /// an extended WebP holding these chunks
+    fn webp(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body = chunks.concat();
+        let mut webp = b"RIFF".to_vec();
+        webp.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        webp.extend_from_slice(b"WEBP");
+        webp.extend_from_slice(&body);
+        webp
+    }
+
+    /// the VP8X header of a 16x8 WebP with these flags
+    fn vp8x(flags: u8) -> Vec<u8> {
+        riff_chunk(b"VP8X", &[flags, 0, 0, 0, 15, 0, 0, 7, 0, 0])
+    }
+
+    #[test]
+    fn webp_loses_exif_and_xmp() {
+        let plain = encode_image(&picture(), ImageFormat::WebP).unwrap();
+        // the image data chunk of the simple file
+        let data = plain[12..].to_vec();
+        let icc = riff_chunk(b"ICCP", b"fake profile");
+        // ICC, alpha, EXIF and XMP, the EXIF chunk is padded to an even length
+        let exif = [TIFF, b"\0"].concat();
+        let original = webp(&[
+            vp8x(0x3C),
+            icc.clone(),
+            data.clone(),
+            riff_chunk(b"EXIF", &exif),
+            riff_chunk(b"XMP ", XMP),
+        ]);
+        let original = [original, b"trailing data".to_vec()].concat();
+
+        let stripped = strip_metadata(&original, ImageFormat::WebP, None).unwrap();
+        assert!(!contains(&stripped, b"EXIF"));
+        assert!(!contains(&stripped, b"secret place"));
+        assert!(!contains(&stripped, b"trailing data"));
+        assert_eq!(stripped, webp(&[vp8x(0x30), icc, data]));
+        same_pixels(&stripped, &plain);
+    }
+
+    #[test]
+    fn truncated_webp_is_refused() {
+        let plain = encode_image(&picture(), ImageFormat::WebP).unwrap();
+        assert!(strip_webp(&plain[..plain.len() - 4]).is_err());
+        let mut wrong_length = plain.clone();
+        wrong_length[16] = wrong_length[16].wrapping_add(2);
+        assert!(strip_webp(&wrong_length).is_err());
+        assert!(strip_webp(b"RIFF\0\0\0\0WAVE").is_err());
+    }
+}
@@ -0,0 +1,557 @@
+//! The tag query language used by `/search`:
+//!
+//! ```text
+//! cat AND (outdoor OR garden) NOT blurry
+//! sun*
+//! ```
+//!
+//! - `AND`, `OR` and `NOT` are keywords when written in uppercase
+//! - two terms next to each other are joined by an implicit `AND`
+//! - `a NOT b` means `a AND NOT b`
+//! - a term ending with `*` matches every tag starting with it
+//! - `AND` binds tighter than `OR`, parentheses group
+//!
+//! A query is parsed into an `Expr` and turned into SQL with bound parameters,
+//! the text of the query never ends up in the SQL itself.
+
+use sqlx::{QueryBuilder, Sqlite};
+
+/// deepest nesting of parentheses and `NOT` we accept
+const MAX_DEPTH: usize = 32;
+/// most terms a query may contain, each one is a sub query
+const MAX_TERMS: usize = 64;
+
+/// A parsed tag query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    /// images having exactly this tag
+    Tag(String),
+    /// images having a tag starting with this prefix
+    Prefix(String),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+/// Why a query can't be parsed, and where
+#[derive(Debug, Clone)]
+pub struct QueryError {
+    /// position of the offending token, in characters
+    pub position: usize,
+    pub message: String,
+    query: String,
+}
+
+impl std::fmt::Display for QueryError {
+    /// the message, followed by the query and a caret under the offending token
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} at position {}:", self.message, self.position)?;
+        writeln!(f, "{}", self.query)?;
+        write!(f, "{}^", " ".repeat(self.position))
+    }
+}
+
+impl Expr {
+    /// append the SQL condition matching this expression, for a query on `images`
+    pub fn push_sql(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        match self {
+            Expr::Tag(tag) => {
+                query
+                    .push(
+                        "images.id IN (SELECT image_tags.image_id FROM image_tags
+                         JOIN tags ON tags.id = image_tags.tag_id WHERE tags.name = ",
+                    )
+                    .push_bind(tag.clone())
+                    .push(")");
+            }
+            Expr::Prefix(prefix) => {
+                query
+                    .push(
+                        "images.id IN (SELECT image_tags.image_id FROM image_tags
+                         JOIN tags ON tags.id = image_tags.tag_id WHERE tags.name LIKE ",
+                    )
+                    .push_bind(format!("{}%", escape_like(prefix)))
+                    .push(" ESCAPE '\\')");
+            }
+            Expr::And(left, right) => {
+                query.push("(");
+                left.push_sql(query);
+                query.push(" AND ");
+                right.push_sql(query);
+                query.push(")");
+            }
+            Expr::Or(left, right) => {
+                query.push("(");
+                left.push_sql(query);
+                query.push(" OR ");
+                right.push_sql(query);
+                query.push(")");
+            }
+            Expr::Not(inner) => {
+                query.push("NOT (");
+                inner.push_sql(query);
+                query.push(")");
+            }
+        }
+    }
+}
+
+/// escape the wildcards of LIKE, so they match themselves
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Parse a query. An empty query (only whitespace) gives `None`, meaning "everything".
+pub fn parse(text: &str) -> Result<Option<Expr>, QueryError> {
+    let tokens = tokenize(text);
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let mut parser = Parser {
+        text,
+        tokens,
+        next: 0,
+        depth: 0,
+        terms: 0,
+    };
+    let expr = parser.or()?;
+    if let Some(token) = parser.peek() {
+        return Err(parser.error_at(token.position, format!("Unexpected {}", token.kind)));
+    }
+    Ok(Some(expr))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TokenKind {
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Term(String),
+}
+
+impl std::fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenKind::And => write!(f, "AND"),
+            TokenKind::Or => write!(f, "OR"),
+            TokenKind::Not => write!(f, "NOT"),
+            TokenKind::Open => write!(f, "'('"),
+            TokenKind::Close => write!(f, "')'"),
+            TokenKind::Term(term) => write!(f, "{term:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    /// position of the first character, in characters
+    position: usize,
+}
+
+/// Cut the query into tokens. Like tags, terms are separated by whitespace or commas.
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().enumerate().peekable();
+
+    while let Some((position, c)) = chars.next() {
+        let kind = match c {
+            c if c.is_whitespace() || c == ',' => continue,
+            '(' => TokenKind::Open,
+            ')' => TokenKind::Close,
+            _ => {
+                let mut word = String::from(c);
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, ',' | '(' | ')') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                match word.as_str() {
+                    "AND" => TokenKind::And,
+                    "OR" => TokenKind::Or,
+                    "NOT" => TokenKind::Not,
+                    _ => TokenKind::Term(word),
+                }
+            }
+        };
+        tokens.push(Token { kind, position });
+    }
+    tokens
+}
+
+/// A recursive descent parser, one method per precedence level
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<Token>,
+    next: usize,
+    depth: usize,
+    terms: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        token
+    }
+
+    fn error_at(&self, position: usize, message: String) -> QueryError {
+        QueryError {
+            position,
+            message,
+            query: self.text.to_string(),
+        }
+    }
+
+    /// position just after the last character, for "unexpected end of query"
+    fn end(&self) -> usize {
+        self.text.chars().count()
+    }
+
+    /// or := and ("OR" and)*
+    fn or(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.and()?;
+        while self.peek().is_some_and(|t| t.kind == TokenKind::Or) {
+            self.advance();
+            let right = self.and()?;
+            expr = Expr::Or(Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    /// and := unary (["AND"] unary | "NOT" unary)*
+    fn and(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.unary()?;
+        while let Some(token) = self.peek() {
+            let right = match token.kind {
+                TokenKind::And => {
+                    self.advance();
+                    self.unary()?
+                }
+                // "a NOT b" is "a AND NOT b", unary() reads the NOT
+                TokenKind::Not | TokenKind::Open | TokenKind::Term(_) => self.unary()?,
+                TokenKind::Or | TokenKind::Close => break,
+            };
+            expr = Expr::And(Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    /// unary := "NOT" unary | primary
+    fn unary(&mut self) -> Result<Expr, QueryError> {
+        if self.peek().is_some_and(|t| t.kind == TokenKind::Not) {
+            let token = self.advance().expect("peeked");
+            self.enter(token.position)?;
+            let inner = self.unary()?;
+            self.depth -= 1;
+            return Ok(Expr::Not(Box::new(inner)));
+        }
+        self.primary()
+    }
+
+    /// primary := "(" or ")" | term
+    fn primary(&mut self) -> Result<Expr, QueryError> {
+        let Some(token) = self.advance() else {
+            return Err(self.error_at(self.end(), "Unexpected end of query".to_string()));
+        };
+
+        match token.kind {
+            TokenKind::Open => {
+                self.enter(token.position)?;
+                let expr = self.or()?;
+                self.depth -= 1;
+                match self.advance() {
+                    Some(Token {
+                        kind: TokenKind::Close,
+                        ..
+                    }) => Ok(expr),
+                    Some(other) => Err(self.error_at(
+                        other.position,
+                        format!("Expected ')' but found {}", other.kind),
+                    )),
+                    None => Err(self.error_at(
+                        token.position,
+                        "This parenthesis is never closed".to_string(),
+                    )),
+                }
+            }
+            TokenKind::Term(term) => self.term(term, token.position),
+            other => {
+                Err(self.error_at(token.position, format!("Expected a tag but found {other}")))
+            }
+        }
+    }
+
+    /// a tag, or a prefix when it ends with `*`
+    fn term(&mut self, term: String, position: usize) -> Result<Expr, QueryError> {
+        self.terms += 1;
+        if self.terms > MAX_TERMS {
+            return Err(self.error_at(
+                position,
+                format!("Too many tags, at most {MAX_TERMS} are allowed"),
+            ));
+        }
+
+        let term = term.to_lowercase();
+        let (name, prefix) = match term.strip_suffix('*') {
+            Some(name) => (name, true),
+            None => (term.as_str(), false),
+        };
+        if let Some(offset) = name.chars().position(|c| c == '*') {
+            return Err(self.error_at(
+                position + offset,
+                "'*' is only allowed at the end of a tag".to_string(),
+            ));
+        }
+        if name.is_empty() {
+            return Err(self.error_at(position, "'*' needs a prefix, such as sun*".to_string()));
+        }
+
+        Ok(if prefix {
+            Expr::Prefix(name.to_string())
+        } else {
+            Expr::Tag(name.to_string())
+        })
+    }
+
+    /// go one level deeper, refusing queries nested too deeply
+    fn enter(&mut self, position: usize) -> Result<(), QueryError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error_at(
+                position,
+                format!("The query is nested too deeply, at most {MAX_DEPTH} levels are allowed"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_pool;
+
+    fn tag(name: &str) -> Box<Expr> {
+        Box::new(Expr::Tag(name.to_string()))
+    }
+
+    fn parsed(text: &str) -> Expr {
+        parse(text).unwrap().unwrap()
+    }
+
+    fn error(text: &str) -> QueryError {
+        parse(text).unwrap_err()
+    }
+
+    /// the SQL of an expression, whitespace collapsed
+    fn sql(expr: &Expr) -> String {
+        let mut query = QueryBuilder::<Sqlite>::new("");
+        expr.push_sql(&mut query);
+        query.sql().split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert_eq!(parse("").unwrap(), None);
+        assert_eq!(parse(" ,\t").unwrap(), None);
+    }
+
+    #[test]
+    fn terms_next_to_each_other_are_and() {
+        assert_eq!(parsed("cat dog"), Expr::And(tag("cat"), tag("dog")));
+        assert_eq!(parsed("cat,dog"), parsed("cat AND dog"));
+        assert_eq!(
+            parsed("a b c"),
+            Expr::And(Box::new(Expr::And(tag("a"), tag("b"))), tag("c"))
+        );
+    }
+
+    #[test]
+    fn not_after_a_term_is_and_not() {
+        assert_eq!(
+            parsed("a NOT b"),
+            Expr::And(tag("a"), Box::new(Expr::Not(tag("b"))))
+        );
+        assert_eq!(parsed("a NOT b"), parsed("a AND NOT b"));
+        assert_eq!(
+            parsed("NOT NOT a"),
+            Expr::Not(Box::new(Expr::Not(tag("a"))))
+        );
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            parsed("a OR b AND c"),
+            Expr::Or(tag("a"), Box::new(Expr::And(tag("b"), tag("c"))))
+        );
+        assert_eq!(
+            parsed("a b OR c"),
+            Expr::Or(Box::new(Expr::And(tag("a"), tag("b"))), tag("c"))
+        );
+        assert_eq!(
+            parsed("(a OR b) c"),
+            Expr::And(Box::new(Expr::Or(tag("a"), tag("b"))), tag("c"))
+        );
+        assert_eq!(
+            parsed("NOT a OR b"),
+            Expr::Or(Box::new(Expr::Not(tag("a"))), tag("b"))
+        );
+    }
+
+    #[test]
+    fn keywords_are_uppercase_and_terms_lowercased() {
+        assert_eq!(parsed("Cat and"), Expr::And(tag("cat"), tag("and")));
+        assert_eq!(parsed("or"), Expr::Tag("or".to_string()));
+    }
+
+    #[test]
+    fn star_ends_a_prefix() {
+        assert_eq!(parsed("Sun*"), Expr::Prefix("sun".to_string()));
+
+        let e = error("su*n");
+        assert_eq!(e.position, 2);
+        assert_eq!(e.message, "'*' is only allowed at the end of a tag");
+        let e = error("sun**");
+        assert_eq!(e.position, 3);
+        let e = error("cat *");
+        assert_eq!(e.position, 4);
+        assert_eq!(e.message, "'*' needs a prefix, such as sun*");
+    }
+
+    #[test]
+    fn positions_count_characters() {
+        // "été" is 3 characters but 5 bytes
+        assert_eq!(error("été x*y").position, 5);
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_token() {
+        let e = error("cat AND");
+        assert_eq!(
+            (e.position, e.message.as_str()),
+            (7, "Unexpected end of query")
+        );
+        let e = error("cat (dog");
+        assert_eq!(
+            (e.position, e.message.as_str()),
+            (4, "This parenthesis is never closed")
+        );
+        let e = error("cat )");
+        assert_eq!((e.position, e.message.as_str()), (4, "Unexpected ')'"));
+        let e = error("cat OR OR dog");
+        assert_eq!(
+            (e.position, e.message.as_str()),
+            (7, "Expected a tag but found OR")
+        );
+        let e = error("(cat dog");
+        assert_eq!(e.position, 0);
+    }
+
+    #[test]
+    fn caret_is_under_the_token() {
+        assert_eq!(
+            error("cat su*n").to_string(),
+            "'*' is only allowed at the end of a tag at position 6:\ncat su*n\n      ^"
+        );
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nots = |n| format!("{}a", "NOT ".repeat(n));
+        assert!(parse(&nots(MAX_DEPTH)).is_ok());
+        let e = error(&nots(MAX_DEPTH + 1));
+        assert_eq!(e.position, MAX_DEPTH * 4);
+
+        let parens = |n| format!("{}a{}", "(".repeat(n), ")".repeat(n));
+        assert!(parse(&parens(MAX_DEPTH)).is_ok());
+        let e = error(&parens(MAX_DEPTH + 1));
+        assert_eq!(e.position, MAX_DEPTH);
+        assert!(e.message.contains("nested too deeply"));
+
+        // siblings don't add up
+        let siblings = vec![parens(MAX_DEPTH); 2].join(" OR ");
+        assert!(parse(&siblings).is_ok());
+    }
+
+    #[test]
+    fn terms_are_limited() {
+        let terms = |n: usize| (0..n).map(|i| format!("t{i}")).collect::<Vec<_>>();
+        assert!(parse(&terms(MAX_TERMS).join(" OR ")).is_ok());
+
+        let text = terms(MAX_TERMS + 1).join(" ");
+        let e = error(&text);
+        assert_eq!(e.position, text.rfind("t64").unwrap());
+        assert_eq!(e.message, "Too many tags, at most 64 are allowed");
+    }
+
+    #[test]
+    fn nested_query_sql() {
+        let tag = "images.id IN (SELECT image_tags.image_id FROM image_tags \
+                   JOIN tags ON tags.id = image_tags.tag_id WHERE tags.name = ?)";
+        let prefix = "images.id IN (SELECT image_tags.image_id FROM image_tags \
+                      JOIN tags ON tags.id = image_tags.tag_id WHERE tags.name LIKE ? ESCAPE '\\')";
+        assert_eq!(
+            sql(&parsed("cat (dog OR sun*) NOT blurry")),
+            format!("(({tag} AND ({tag} OR {prefix})) AND NOT ({tag}))")
+        );
+    }
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        assert_eq!(escape_like(r"50%_off\"), r"50\%\_off\\");
+    }
+
+    /// ids of the images of `images` matching a query
+    async fn matching(pool: &sqlx::SqlitePool, text: &str) -> Vec<i64> {
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT id FROM images WHERE ");
+        parsed(text).push_sql(&mut query);
+        query.push(" ORDER BY id");
+        query.build_query_scalar().fetch_all(pool).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn nested_query_selects_the_images() {
+        let pool = test_pool().await;
+        sqlx::raw_sql(
+            "INSERT INTO images (id) VALUES (1), (2), (3), (4), (5);
+             INSERT INTO tags (id, name) VALUES
+                 (1, 'cat'), (2, 'dog'), (3, 'sunset'), (4, 'blurry'), (5, 'sun_'), (6, 'sunny');
+             INSERT INTO image_tags (image_id, tag_id) VALUES
+                 (1, 1), (1, 2),
+                 (2, 1), (2, 3),
+                 (3, 1), (3, 3), (3, 4),
+                 (4, 2),
+                 (5, 1), (5, 5);",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(
+            matching(&pool, "cat (dog OR sun*) NOT blurry").await,
+            [1, 2, 5]
+        );
+        assert_eq!(matching(&pool, "dog OR blurry").await, [1, 3, 4]);
+        assert_eq!(matching(&pool, "NOT cat").await, [4]);
+        // `_` is not a wildcard of the prefix
+        assert_eq!(matching(&pool, "sun_*").await, [5]);
+    }
+}
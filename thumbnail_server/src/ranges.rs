@@ -0,0 +1,355 @@
+//! HTTP range requests (RFC 9110 14), so an interrupted download can be resumed
+//! instead of starting again: `Range: bytes=1000-` gives a `206 Partial Content`
+//! with the rest of the file, several ranges give a `multipart/byteranges` body.
+
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+
+use crate::{error::AppError, storage::SharedStorage};
+
+/// More ranges than this are answered with the whole file, asking for many
+/// tiny pieces is a cheap way to make a server work a lot.
+const MAX_RANGES: usize = 16;
+
+/// Why the Range header of a request can't be used
+#[derive(Debug, PartialEq, Eq)]
+enum RangeError {
+    /// not a list of byte ranges, the header is ignored
+    Invalid,
+    /// none of the ranges is inside the file, answered with a 416
+    Unsatisfiable,
+}
+
+/// The Range header a response should follow, `None` when the whole file is sent.
+/// With `If-Range` the range only applies when the client still has the current
+/// version of the file, given by the `etag` and `last_modified` of the response:
+/// a file that changed is sent whole.
+pub fn requested_range<'a>(
+    request: &'a HeaderMap,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> Option<&'a str> {
+    let range = request.get(header::RANGE)?.to_str().ok()?;
+    let Some(if_range) = request.get(header::IF_RANGE) else {
+        return Some(range);
+    };
+    let if_range = if_range.to_str().ok()?.trim();
+    // an entity tag is compared strongly, a weak one never matches
+    let matches = if if_range.starts_with('"') {
+        Some(if_range) == etag
+    } else {
+        Some(if_range) == last_modified
+    };
+    matches.then_some(range)
+}
+
+/// Answer a Range header: one range gives a 206 with that part of the file,
+/// several a 206 with a `multipart/byteranges` body, none inside the file a 416.
+/// Gives `None` when the header can't be understood, the whole file is then sent.
+pub async fn serve_ranges(
+    storage: &SharedStorage,
+    key: &str,
+    content_type: &str,
+    range: &str,
+) -> Result<Option<Response>, AppError> {
+    let Some(size) = storage.size(key).await? else {
+        return Err(AppError::NotFound(format!("{key} not found")));
+    };
+
+    let ranges = match parse_ranges(range, size) {
+        Ok(ranges) => ranges,
+        Err(RangeError::Invalid) => return Ok(None),
+        Err(RangeError::Unsatisfiable) => {
+            let response = Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{size}"))
+                .body(Body::empty())?;
+            return Ok(Some(response));
+        }
+    };
+
+    if let [range] = ranges.as_slice() {
+        let Some(body) = storage.stream_range(key, range.clone()).await? else {
+            return Err(AppError::NotFound(format!("{key} not found")));
+        };
+        let response = Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_RANGE, content_range(range, size))
+            .header(header::CONTENT_LENGTH, range.end - range.start)
+            .body(Body::from_stream(body))?;
+        return Ok(Some(response));
+    }
+
+    // every part starts with its own headers, and the body ends with a closing boundary
+    let boundary = boundary();
+    let parts: Vec<(Bytes, Range<u64>)> = ranges
+        .into_iter()
+        .map(|range| {
+            let headers = format!(
+                "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+                content_range(&range, size)
+            );
+            (Bytes::from(headers), range)
+        })
+        .collect();
+    let end = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+    let length = parts
+        .iter()
+        .map(|(headers, range)| headers.len() as u64 + range.end - range.start)
+        .sum::<u64>()
+        + end.len() as u64;
+
+    let storage = storage.clone();
+    let key = key.to_string();
+    let body = stream::iter(parts)
+        .then(move |(headers, range)| {
+            let storage = storage.clone();
+            let key = key.clone();
+            async move {
+                let data = match storage.stream_range(&key, range).await {
+                    Ok(Some(data)) => data,
+                    Ok(None) => stream::once(async move {
+                        Err(std::io::Error::other(format!("{key} not found")))
+                    })
+                    .boxed(),
+                    Err(e) => stream::once(async move { Err(std::io::Error::other(e)) }).boxed(),
+                };
+                stream::once(async move { Ok(headers) }).chain(data)
+            }
+        })
+        .flatten()
+        .chain(stream::once(async move { Ok(end) }));
+
+    let response = Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={boundary}"),
+        )
+        .header(header::CONTENT_LENGTH, length)
+        .body(Body::from_stream(body))?;
+    Ok(Some(response))
+}
+
+/// Parse `bytes=0-99, 200-, -50` into the ranges of a file of `size` bytes.
+/// The ranges outside the file are dropped, the overlapping ones are merged.
+fn parse_ranges(header: &str, size: u64) -> Result<Vec<Range<u64>>, RangeError> {
+    let (unit, specs) = header.split_once('=').ok_or(RangeError::Invalid)?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Err(RangeError::Invalid);
+    }
+
+    if specs.trim().is_empty() {
+        return Err(RangeError::Invalid);
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (first, last) = spec.split_once('-').ok_or(RangeError::Invalid)?;
+        let number = |text: &str| text.trim().parse::<u64>().map_err(|_| RangeError::Invalid);
+        let range = match (first.trim(), last.trim()) {
+            // the last n bytes
+            ("", suffix) => {
+                let length = number(suffix)?;
+                size.saturating_sub(length)..size
+            }
+            (first, "") => number(first)?..size,
+            (first, last) => {
+                let (first, last) = (number(first)?, number(last)?);
+                if last < first {
+                    return Err(RangeError::Invalid);
+                }
+                first..last.saturating_add(1).min(size)
+            }
+        };
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+    if ranges.is_empty() {
+        return Err(RangeError::Unsatisfiable);
+    }
+    if ranges.len() > MAX_RANGES {
+        return Err(RangeError::Invalid);
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Ok(merged)
+}
+
+/// the Content-Range of a part of a file, its last byte is included
+fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("bytes {}-{}/{size}", range.start, range.end - 1)
+}
+
+/// a separator of the parts that won't show up in the data by chance
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let unique = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos() as u64);
+    format!("range-{nanos:016x}-{unique}")
+}
+
+#[cfg(test)]
+// `vec![0..100]` is one range of the file, not the numbers in it
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const SIZE: u64 = 1000;
+
+    fn parse(header: &str) -> Result<Vec<Range<u64>>, RangeError> {
+        parse_ranges(header, SIZE)
+    }
+
+    fn headers(range: &str, if_range: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_str(range).unwrap());
+        if let Some(if_range) = if_range {
+            headers.insert(header::IF_RANGE, HeaderValue::from_str(if_range).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn closed_ranges_include_the_last_byte() {
+        assert_eq!(parse("bytes=0-99"), Ok(vec![0..100]));
+        assert_eq!(parse("bytes=999-999"), Ok(vec![999..1000]));
+        // the end is cut to the file
+        assert_eq!(parse("bytes=900-5000"), Ok(vec![900..1000]));
+        assert_eq!(parse("BYTES = 0-0"), Ok(vec![0..1]));
+    }
+
+    #[test]
+    fn open_ended_ranges_go_to_the_end() {
+        assert_eq!(parse("bytes=100-"), Ok(vec![100..1000]));
+        assert_eq!(parse("bytes=0-"), Ok(vec![0..1000]));
+    }
+
+    #[test]
+    fn suffix_ranges_are_the_last_bytes() {
+        assert_eq!(parse("bytes=-50"), Ok(vec![950..1000]));
+        // longer than the file, the whole file
+        assert_eq!(parse("bytes=-5000"), Ok(vec![0..1000]));
+        assert_eq!(parse("bytes=-0"), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse_ranges("bytes=-10", 0), Err(RangeError::Unsatisfiable));
+    }
+
+    #[test]
+    fn malformed_headers_are_invalid() {
+        assert_eq!(parse("bytes=200-100"), Err(RangeError::Invalid));
+        assert_eq!(parse("bytes=0-99, 50-10"), Err(RangeError::Invalid));
+        assert_eq!(parse("items=0-99"), Err(RangeError::Invalid));
+        assert_eq!(parse("0-99"), Err(RangeError::Invalid));
+        assert_eq!(parse("bytes="), Err(RangeError::Invalid));
+        assert_eq!(parse("bytes=100"), Err(RangeError::Invalid));
+        assert_eq!(parse("bytes=a-b"), Err(RangeError::Invalid));
+        assert_eq!(parse("bytes=-"), Err(RangeError::Invalid));
+        assert_eq!(parse("bytes=--5"), Err(RangeError::Invalid));
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        assert_eq!(parse("bytes=0-99, 50-149"), Ok(vec![0..150]));
+        // adjacent ranges too, and in any order
+        assert_eq!(parse("bytes=100-199, 0-99"), Ok(vec![0..200]));
+        assert_eq!(parse("bytes=500-, -600"), Ok(vec![400..1000]));
+        assert_eq!(parse("bytes=10-20, 0-99"), Ok(vec![0..100]));
+        assert_eq!(parse("bytes=0-9, 20-29, 5-14"), Ok(vec![0..15, 20..30]));
+    }
+
+    #[test]
+    fn too_many_ranges_give_the_whole_file() {
+        let ranges = |n: u64| {
+            let specs: Vec<_> = (0..n).map(|i| format!("{}-{}", i * 10, i * 10)).collect();
+            format!("bytes={}", specs.join(","))
+        };
+        assert_eq!(parse(&ranges(MAX_RANGES as u64)).unwrap().len(), MAX_RANGES);
+        assert_eq!(
+            parse(&ranges(MAX_RANGES as u64 + 1)),
+            Err(RangeError::Invalid)
+        );
+    }
+
+    #[test]
+    fn ranges_outside_the_file_are_unsatisfiable() {
+        assert_eq!(parse("bytes=1000-"), Err(RangeError::Unsatisfiable));
+        assert_eq!(
+            parse("bytes=1000-1999, 5000-"),
+            Err(RangeError::Unsatisfiable)
+        );
+        // the ones inside are kept
+        assert_eq!(parse("bytes=1000-, 0-9"), Ok(vec![0..10]));
+    }
+
+    #[test]
+    fn content_range_names_the_last_byte() {
+        assert_eq!(content_range(&(0..100), SIZE), "bytes 0-99/1000");
+    }
+
+    #[test]
+    fn range_without_if_range_applies() {
+        let request = headers("bytes=0-99", None);
+        assert_eq!(
+            requested_range(&request, Some("\"abc\""), None),
+            Some("bytes=0-99")
+        );
+        assert_eq!(requested_range(&HeaderMap::new(), None, None), None);
+    }
+
+    #[test]
+    fn if_range_with_an_etag() {
+        let etag = Some("\"abc\"");
+        let date = Some("Wed, 21 Oct 2015 07:28:00 GMT");
+        let request = headers("bytes=0-99", Some("\"abc\""));
+        assert_eq!(requested_range(&request, etag, date), Some("bytes=0-99"));
+        let request = headers("bytes=0-99", Some("\"old\""));
+        assert_eq!(requested_range(&request, etag, date), None);
+        // not compared with the date
+        let request = headers("bytes=0-99", Some("\"abc\""));
+        assert_eq!(requested_range(&request, None, date), None);
+    }
+
+    #[test]
+    fn if_range_with_a_date() {
+        let etag = Some("\"abc\"");
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let request = headers("bytes=0-99", Some(date));
+        assert_eq!(
+            requested_range(&request, etag, Some(date)),
+            Some("bytes=0-99")
+        );
+        let request = headers("bytes=0-99", Some("Thu, 22 Oct 2015 07:28:00 GMT"));
+        assert_eq!(requested_range(&request, etag, Some(date)), None);
+        assert_eq!(requested_range(&request, etag, None), None);
+    }
+
+    #[test]
+    fn if_range_with_a_weak_etag_never_matches() {
+        let request = headers("bytes=0-99", Some("W/\"abc\""));
+        assert_eq!(requested_range(&request, Some("W/\"abc\""), None), None);
+        assert_eq!(requested_range(&request, Some("\"abc\""), None), None);
+    }
+}
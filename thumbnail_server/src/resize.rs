@@ -0,0 +1,279 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::QueryRejection, Path, Query},
+    http::HeaderMap,
+    response::Response,
+    Extension,
+};
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use serde::Deserialize;
+use tokio::{sync::Semaphore, task::spawn_blocking};
+
+use crate::{
+    caching::{self, VersionQuery},
+    config::Config,
+    error::AppError,
+    files::image_file,
+    imaging::{encode_image, format_from_name, load_image, DecodeBudget, OUTPUT_FORMATS},
+    storage::{SharedStorage, Storage},
+};
+
+/// folder where the generated renditions are cached, in a sub folder per image
+const CACHE_DIR: &str = "cache";
+
+/// How the image is fitted into the requested box
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// fill the whole box, cropping what doesn't fit
+    Cover,
+    /// fit inside the box, keeping the aspect ratio
+    #[default]
+    Contain,
+    /// stretch the image to the exact size of the box
+    Fill,
+}
+
+impl Fit {
+    fn name(self) -> &'static str {
+        match self {
+            Fit::Cover => "cover",
+            Fit::Contain => "contain",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+/// Makes the renditions, a bounded number at the same time: each one decodes,
+/// resizes and encodes a whole original
+#[derive(Clone)]
+pub struct Renderer {
+    renders: Arc<Semaphore>,
+    budget: DecodeBudget,
+}
+
+impl Renderer {
+    pub fn new(renders: usize, budget: DecodeBudget) -> Self {
+        Renderer {
+            renders: Arc::new(Semaphore::new(renders)),
+            budget,
+        }
+    }
+
+    /// decode an original, the right way up, and encode what `make` turns it into
+    async fn render(
+        &self,
+        original: Vec<u8>,
+        make: impl FnOnce(DynamicImage) -> anyhow::Result<Vec<u8>> + Send + 'static,
+    ) -> anyhow::Result<Vec<u8>> {
+        let _render = self.renders.acquire().await?;
+        let decoding = self.budget.reserve_for(&original).await;
+        spawn_blocking(move || {
+            let _decoding = decoding;
+            make(load_image(&original)?)
+        })
+        .await?
+    }
+}
+
+/// query string of `/resize/:id?w=&h=&fit=&format=`
+#[derive(Deserialize, Debug)]
+pub struct ResizeQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+    #[serde(default)]
+    fit: Fit,
+    format: Option<String>,
+    /// the version of the thumbnails the URL was built for, see `caching`
+    v: Option<String>,
+}
+
+/// Resize an image to an arbitrary size and return the response.
+/// Every rendition is generated once and then served from the cache.
+/// Renditions are dropped with the thumbnails, they share their version
+/// and so their caching headers (see `caching`).
+pub async fn resize_image(
+    Path(id): Path<i64>,
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(renderer): Extension<Renderer>,
+    headers: HeaderMap,
+    query: Result<Query<ResizeQuery>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let Query(query) = query.map_err(|e| AppError::BadRequest(e.body_text()))?;
+
+    // check everything before touching the storage
+    if query.w.is_none() && query.h.is_none() {
+        return Err(AppError::BadRequest(
+            "At least one of w or h is required".to_string(),
+        ));
+    }
+    check_size("w", query.w, config.resize_max_width, &config)?;
+    check_size("h", query.h, config.resize_max_height, &config)?;
+    let requested_format = match &query.format {
+        Some(name) => Some(
+            format_from_name(name)
+                .ok_or_else(|| AppError::BadRequest(format!("Unsupported format: {name}")))?,
+        ),
+        None => None,
+    };
+
+    let file = image_file(&pool, id).await?;
+    let format = requested_format.unwrap_or(output_format(file.format));
+
+    // the cache key holds every parameter that changes the output
+    let variant = format!(
+        "{}x{}_{}.{}",
+        query.w.unwrap_or(0),
+        query.h.unwrap_or(0),
+        query.fit.name(),
+        format.extensions_str()[0],
+    );
+    let cached = format!("{CACHE_DIR}/{id}/{variant}");
+
+    if !storage.exists(&cached).await? {
+        let original = read_original(storage.as_ref(), &file.key(), id).await?;
+        let (w, h, fit) = (query.w, query.h, query.fit);
+        let bytes = renderer
+            .render(original, move |image| {
+                encode_image(&resize(&image, w, h, fit), format)
+            })
+            .await?;
+        storage.put(&cached, bytes).await?;
+    }
+
+    let version = VersionQuery { v: query.v };
+    let policy = caching::thumbnails_version(&pool, id)
+        .await?
+        .map(|thumbnails| thumbnails.policy(Some(&variant), &version));
+    let content_type = format.to_mime_type();
+    caching::serve_cached(&storage, &cached, content_type, &headers, policy, &config).await
+}
+
+/// read the original of an image to make a rendition of it
+async fn read_original(storage: &dyn Storage, key: &str, id: i64) -> Result<Vec<u8>, AppError> {
+    storage
+        .get(key)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Image {id} not found")))
+}
+
+/// keep the format of the original when we know how to encode it
+fn output_format(original_format: ImageFormat) -> ImageFormat {
+    if OUTPUT_FORMATS.contains(&original_format) {
+        original_format
+    } else {
+        ImageFormat::Jpeg
+    }
+}
+
+/// Show the original the right way up. Only the images whose EXIF says they
+/// are rotated or mirrored are re-encoded (once, then cached), the others
+/// are served as they are, with the caching headers of the original.
+pub async fn display_image(
+    Path(id): Path<i64>,
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(renderer): Extension<Renderer>,
+    headers: HeaderMap,
+    version: Result<Query<VersionQuery>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let Query(version) = version.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let file = image_file(&pool, id).await?;
+
+    // unknown until the metadata of an old image is read, show it as it is meanwhile
+    let orientation: Option<u32> =
+        sqlx::query_scalar("SELECT orientation FROM image_metadata WHERE image_id = ?")
+            .bind(id)
+            .fetch_optional(&pool)
+            .await?
+            .flatten();
+    if orientation.unwrap_or(1) == 1 {
+        let policy = caching::image_version(&pool, id)
+            .await?
+            .map(|original| original.policy(None, &version));
+        let content_type = file.format.to_mime_type();
+        return caching::serve_cached(
+            &storage,
+            &file.key(),
+            content_type,
+            &headers,
+            policy,
+            &config,
+        )
+        .await;
+    }
+
+    let format = output_format(file.format);
+    let variant = format!("display.{}", format.extensions_str()[0]);
+    let cached = format!("{CACHE_DIR}/{id}/{variant}");
+    if !storage.exists(&cached).await? {
+        let original = read_original(storage.as_ref(), &file.key(), id).await?;
+        let bytes = renderer
+            .render(original, move |image| encode_image(&image, format))
+            .await?;
+        storage.put(&cached, bytes).await?;
+    }
+
+    let policy = caching::thumbnails_version(&pool, id)
+        .await?
+        .map(|thumbnails| thumbnails.policy(Some(&variant), &version));
+    let content_type = format.to_mime_type();
+    caching::serve_cached(&storage, &cached, content_type, &headers, policy, &config).await
+}
+
+/// Forget the cached renditions of an image, they are generated again when asked.
+/// Needed when they were made from an outdated reading of the original.
+pub async fn remove_cached_renditions(storage: &dyn Storage, id: i64) -> anyhow::Result<()> {
+    for key in storage.list(&format!("{CACHE_DIR}/{id}/")).await? {
+        storage.delete(&key).await?;
+    }
+    Ok(())
+}
+
+/// Forget the renditions cached before every image had its own folder
+/// (`cache/3_200x0_contain.jpg`), they are never read any more.
+pub async fn remove_legacy_renditions(storage: &dyn Storage) -> anyhow::Result<()> {
+    for key in storage.list(&format!("{CACHE_DIR}/")).await? {
+        storage.delete(&key).await?;
+    }
+    Ok(())
+}
+
+/// make sure a requested dimension is allowed
+fn check_size(name: &str, size: Option<u32>, max: u32, config: &Config) -> Result<(), AppError> {
+    let Some(size) = size else {
+        return Ok(());
+    };
+    if size == 0 || size > max {
+        return Err(AppError::BadRequest(format!(
+            "{name} must be between 1 and {max}"
+        )));
+    }
+    if !config.resize_allowed_sizes.is_empty() && !config.resize_allowed_sizes.contains(&size) {
+        return Err(AppError::BadRequest(format!(
+            "{name}={size} is not an allowed size"
+        )));
+    }
+    Ok(())
+}
+
+/// resize an image, a missing dimension is computed from the aspect ratio
+fn resize(image: &DynamicImage, w: Option<u32>, h: Option<u32>, fit: Fit) -> DynamicImage {
+    let (width, height) = (image.width().max(1) as f64, image.height().max(1) as f64);
+    let (w, h) = match (w, h) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, (w as f64 * height / width).round().max(1.0) as u32),
+        (None, Some(h)) => ((h as f64 * width / height).round().max(1.0) as u32, h),
+        (None, None) => (image.width(), image.height()),
+    };
+
+    match fit {
+        Fit::Cover => image.resize_to_fill(w, h, FilterType::Lanczos3),
+        Fit::Contain => image.resize(w, h, FilterType::Lanczos3),
+        Fit::Fill => image.resize_exact(w, h, FilterType::Lanczos3),
+    }
+}
@@ -0,0 +1,222 @@
+use axum::{
+    extract::{rejection::QueryRejection, Query},
+    response::Html,
+    Extension, Form, Json,
+};
+use serde::Deserialize;
+use sqlx::{QueryBuilder, Sqlite};
+
+use crate::{
+    error::{escape_html, AppError, HtmlError},
+    pagination::{ImagePage, Order, PageRequest, Sort, RANK},
+    query::{self, Expr},
+    ImageRecord, IMAGE_COLUMNS,
+};
+
+/// What images a search is looking for, everything is optional
+#[derive(Debug, Clone, Default)]
+pub struct ImageFilter {
+    /// a parsed tag query
+    pub tags: Option<Expr>,
+    /// a full-text query, see `text_query`
+    pub text: Option<String>,
+    /// smallest resolution, the images whose metadata is unknown don't match
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+}
+
+/// Find the images matching a filter (all the images with an empty one).
+/// Without a page request every match is returned, by relevance for a text search,
+/// in id order otherwise.
+pub async fn find_images(
+    pool: &sqlx::SqlitePool,
+    filter: &ImageFilter,
+    page: Option<&PageRequest>,
+) -> Result<Vec<ImageRecord>, AppError> {
+    let text = filter.text.as_deref();
+    if text.is_none() && page.is_some_and(|page| page.sort == Sort::Relevance) {
+        return Err(AppError::BadRequest(
+            "sort=relevance needs a text search".to_string(),
+        ));
+    }
+
+    let mut query = QueryBuilder::<Sqlite>::new(format!("SELECT {IMAGE_COLUMNS}, "));
+    if let Some(text) = text {
+        query
+            .push(format!(
+                "snippet(images_fts, -1, '{MARK_START}', '{MARK_END}', '…', 12) AS snippet
+                 FROM images JOIN images_fts ON images_fts.rowid = images.id
+                 WHERE images_fts MATCH "
+            ))
+            .push_bind(text.to_string());
+    } else {
+        query.push("NULL AS snippet FROM images WHERE 1 = 1");
+    }
+    if let Some(expr) = &filter.tags {
+        query.push(" AND ");
+        expr.push_sql(&mut query);
+    }
+    if let Some(width) = filter.min_width {
+        query
+            .push(" AND images.id IN (SELECT image_id FROM image_metadata WHERE width >= ")
+            .push_bind(width)
+            .push(")");
+    }
+    if let Some(height) = filter.min_height {
+        query
+            .push(" AND images.id IN (SELECT image_id FROM image_metadata WHERE height >= ")
+            .push_bind(height)
+            .push(")");
+    }
+    match page {
+        Some(page) => {
+            page.push_after(&mut query);
+            page.push_order_and_limit(&mut query);
+        }
+        None if text.is_some() => {
+            query.push(format!(" ORDER BY {RANK}, images.id"));
+        }
+        None => {
+            query.push(" ORDER BY images.id");
+        }
+    }
+
+    let mut images = query
+        .build_query_as::<ImageRecord>()
+        .fetch_all(pool)
+        .await?;
+    for image in &mut images {
+        image.snippet = image.snippet.as_deref().map(highlight);
+    }
+    Ok(images)
+}
+
+/// private use characters marking the matched words in a snippet,
+/// they can't appear in the text and survive the HTML escaping
+const MARK_START: char = '\u{E000}';
+const MARK_END: char = '\u{E001}';
+
+/// escape a snippet for HTML and wrap the matched words in <mark>
+fn highlight(snippet: &str) -> String {
+    escape_html(snippet)
+        .replace(MARK_START, "<mark>")
+        .replace(MARK_END, "</mark>")
+}
+
+/// Turn what a user typed into a full-text query: every word must match,
+/// a word ending with `*` matches as a prefix. Each word is quoted so the
+/// FTS5 syntax (AND, NEAR, column filters...) can't be used by accident.
+/// Gives `None` when there is nothing to search for.
+fn text_query(text: &str) -> Option<String> {
+    let words: Vec<String> = text
+        .split_whitespace()
+        .filter_map(|word| {
+            let (word, prefix) = match word.strip_suffix('*') {
+                Some(word) => (word, true),
+                None => (word, false),
+            };
+            let word = word.replace('"', "");
+            if word.is_empty() {
+                return None;
+            }
+            Some(if prefix {
+                format!("\"{word}\"*")
+            } else {
+                format!("\"{word}\"")
+            })
+        })
+        .collect();
+
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+/// parse a tag query, a syntax error is the client's fault
+fn parse_query(text: &str) -> Result<Option<Expr>, AppError> {
+    query::parse(text).map_err(|e| AppError::BadRequest(e.to_string()))
+}
+
+#[derive(Deserialize)]
+pub struct Search {
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    text: String,
+}
+
+/// use tokio form deserialization that attached a type from a query.
+/// The tags field is a query such as `cat AND (outdoor OR garden) NOT blurry`,
+/// see the `query` module. The text field searches the titles, descriptions
+/// and tags, the best matches come first.
+pub async fn search_images(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Form(form): Form<Search>,
+) -> Result<Html<String>, HtmlError> {
+    let filter = ImageFilter {
+        tags: parse_query(&form.tags)?,
+        text: text_query(&form.text),
+        ..ImageFilter::default()
+    };
+    let rows = find_images(&pool, &filter, None).await?;
+
+    let mut results = String::new();
+    for row in rows {
+        results.push_str(&format!(
+            "<div><a href=\"{}\"><img src='{}' /></a><br />{}",
+            row.image_url,
+            row.thumbnail_url,
+            escape_html(&row.title)
+        ));
+        if let Some(snippet) = &row.snippet {
+            results.push_str(&format!("<br /><small>{snippet}</small>"));
+        }
+        results.push_str("</div>");
+    }
+
+    let path = std::path::Path::new("src/search.html");
+    let mut content = tokio::fs::read_to_string(path).await?;
+    content = content.replace("{results}", &results);
+
+    Ok(Html(content))
+}
+
+/// query string of `/api/search`
+#[derive(Deserialize)]
+pub struct ApiSearch {
+    #[serde(default)]
+    q: String,
+    #[serde(default)]
+    text: String,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    sort: Option<Sort>,
+    order: Option<Order>,
+}
+
+/// same search as the form, for scripts: a page of JSON images,
+/// ask for the next one with `cursor=<next_cursor>`
+pub async fn api_search(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    query: Result<Query<ApiSearch>, QueryRejection>,
+) -> Result<Json<ImagePage>, AppError> {
+    let Query(search) = query.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let filter = ImageFilter {
+        tags: parse_query(&search.q)?,
+        text: text_query(&search.text),
+        min_width: search.min_width,
+        min_height: search.min_height,
+    };
+    // a text search is sorted by relevance unless the client asks otherwise
+    let sort = search
+        .sort
+        .or(filter.text.as_ref().map(|_| Sort::Relevance));
+    let page = PageRequest::new(sort, search.order, search.limit, search.cursor.as_deref())?;
+
+    let images = find_images(&pool, &filter, Some(&page)).await?;
+    Ok(Json(ImagePage::new(images, &page)))
+}
@@ -0,0 +1,200 @@
+//! Near-duplicate detection. Every image gets a perceptual hash (dHash): the
+//! image is shrunk to 9x8 gray pixels and each bit tells whether a pixel is
+//! brighter than its right neighbour. Resized or re-encoded copies of a picture
+//! get the same hash, or one that differs by a few bits.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock, RwLockWriteGuard},
+};
+
+use axum::{
+    extract::{rejection::QueryRejection, Path, Query},
+    Extension, Json,
+};
+use image::{imageops::FilterType, DynamicImage};
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::{config::Config, error::AppError, ImageRecord, IMAGE_COLUMNS};
+
+/// most bits two hashes can differ by, they only have 64
+const MAX_DISTANCE: u32 = 64;
+/// most images returned by `/image/:id/similar`
+const MAX_RESULTS: usize = 100;
+
+/// the dHash of an image
+pub fn perceptual_hash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).into_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// number of bits that differ between two hashes
+fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// The perceptual hashes of the images, kept in memory: every upload and every
+/// `/image/:id/similar` compares a hash to all of them, which would otherwise
+/// read the whole table each time.
+#[derive(Clone)]
+pub struct SimilarIndex {
+    hashes: Arc<RwLock<HashMap<i64, u64>>>,
+}
+
+impl SimilarIndex {
+    /// read the hashes computed so far
+    pub async fn load(pool: &SqlitePool) -> anyhow::Result<Self> {
+        let rows: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT id, perceptual_hash FROM images WHERE perceptual_hash IS NOT NULL",
+        )
+        .fetch_all(pool)
+        .await?;
+        // stored as a signed integer, SQLite has no unsigned 64 bits type
+        let hashes = rows
+            .into_iter()
+            .map(|(id, hash)| (id, hash as u64))
+            .collect();
+        Ok(SimilarIndex {
+            hashes: Arc::new(RwLock::new(hashes)),
+        })
+    }
+
+    /// record the hash of an image, once it is committed
+    pub fn insert(&self, id: i64, hash: u64) {
+        self.write().insert(id, hash);
+    }
+
+    /// forget a deleted image
+    pub fn remove(&self, id: i64) {
+        self.write().remove(&id);
+    }
+
+    /// Find the images whose hash is within `max_distance` bits of `hash`,
+    /// closest first. SQLite can't count bits, so every hash is compared here.
+    pub fn find(&self, hash: u64, max_distance: u32, exclude: Option<i64>) -> Vec<(i64, u32)> {
+        let hashes = self.hashes.read().unwrap_or_else(PoisonError::into_inner);
+        let mut similar: Vec<(i64, u32)> = hashes
+            .iter()
+            .map(|(&id, &other)| (id, distance(hash, other)))
+            .filter(|&(id, d)| d <= max_distance && Some(id) != exclude)
+            .collect();
+        similar.sort_by_key(|&(id, d)| (d, id));
+        similar
+    }
+
+    /// the map is never left half updated, a panic elsewhere doesn't matter
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<i64, u64>> {
+        self.hashes.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// query string of `/image/:id/similar`
+#[derive(Deserialize)]
+pub struct SimilarQuery {
+    /// most bits the hashes can differ by, `SIMILAR_MAX_DISTANCE` by default
+    distance: Option<u32>,
+}
+
+/// An image close to another one
+#[derive(Serialize, Debug)]
+pub struct SimilarImage {
+    /// bits that differ between the two hashes, 0 is (almost) the same picture
+    pub distance: u32,
+    #[serde(flatten)]
+    pub image: ImageRecord,
+}
+
+/// the images that look like an image, closest first
+pub async fn similar_images(
+    Path(id): Path<i64>,
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(index): Extension<SimilarIndex>,
+    query: Result<Query<SimilarQuery>, QueryRejection>,
+) -> Result<Json<Vec<SimilarImage>>, AppError> {
+    let Query(query) = query.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let max_distance = query.distance.unwrap_or(config.similar_max_distance);
+    if max_distance > MAX_DISTANCE {
+        return Err(AppError::BadRequest(format!(
+            "distance must be between 0 and {MAX_DISTANCE}"
+        )));
+    }
+
+    let hash: Option<i64> = sqlx::query_scalar("SELECT perceptual_hash FROM images WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Image {id} not found")))?;
+    let Some(hash) = hash else {
+        return Err(AppError::NotFound(format!(
+            "The perceptual hash of image {id} is not computed yet"
+        )));
+    };
+
+    let mut similar = index.find(hash as u64, max_distance, Some(id));
+    similar.truncate(MAX_RESULTS);
+    if similar.is_empty() {
+        return Ok(Json(Vec::new()));
+    }
+
+    let mut query = QueryBuilder::<Sqlite>::new(format!(
+        "SELECT {IMAGE_COLUMNS}, NULL AS snippet FROM images WHERE images.id IN ("
+    ));
+    let mut ids = query.separated(", ");
+    for (id, _) in &similar {
+        ids.push_bind(id);
+    }
+    query.push(")");
+    let mut images = query
+        .build_query_as::<ImageRecord>()
+        .fetch_all(&pool)
+        .await?;
+
+    // keep the order of the distances
+    let images = similar
+        .iter()
+        .filter_map(|&(id, distance)| {
+            let position = images.iter().position(|image| image.id == id)?;
+            Some(SimilarImage {
+                distance,
+                image: images.swap_remove(position),
+            })
+        })
+        .collect();
+    Ok(Json(images))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closest_images_come_first() {
+        let index = SimilarIndex {
+            hashes: Arc::default(),
+        };
+        index.insert(1, 0b1111);
+        index.insert(2, 0b0111);
+        index.insert(3, 0b0000);
+        index.insert(4, 0b1111);
+        index.insert(5, u64::MAX);
+
+        assert_eq!(index.find(0b1111, 2, None), [(1, 0), (4, 0), (2, 1)]);
+        assert_eq!(index.find(0b1111, 2, Some(1)), [(4, 0), (2, 1)]);
+        assert_eq!(index.find(0, 0, None), [(3, 0)]);
+
+        index.remove(4);
+        index.insert(2, 0b1111);
+        assert_eq!(index.find(0b1111, 0, None), [(1, 0), (2, 0)]);
+    }
+}
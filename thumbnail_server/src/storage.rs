@@ -0,0 +1,423 @@
+//! Where the files (originals, thumbnails, cached renditions...) live. They are
+//! named by keys such as `blobs/ab/ab12....jpg` or `3_small.jpg`, the backend
+//! decides what a key becomes: a file under a folder of this machine, or an
+//! object in an S3 bucket shared by several servers.
+
+use std::{io::SeekFrom, ops::Range, path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use s3::{
+    command::Command,
+    creds::Credentials,
+    request::{tokio_backend::ReqwestRequest, Request},
+    Bucket, Region,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    config::{S3Config, StorageConfig},
+    imaging::write_atomically,
+};
+
+/// the storage shared by the handlers and the workers
+pub type SharedStorage = Arc<dyn Storage>;
+
+/// the bytes of a file, a chunk at a time
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// A stored file being read
+pub struct StoredObject {
+    /// length in bytes
+    pub size: u64,
+    pub body: ByteStream,
+}
+
+/// Operations every storage backend provides. A missing key is not an error:
+/// reading it gives `None`, deleting it does nothing.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// store a file, replacing the previous one. Readers see either
+    /// the old or the new content, never a half written file.
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> anyhow::Result<()>;
+
+    /// read a whole file
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// remove a file
+    async fn delete(&self, key: &str) -> anyhow::Result<()>;
+
+    /// whether a file is stored
+    async fn exists(&self, key: &str) -> anyhow::Result<bool>;
+
+    /// read a file a chunk at a time, to send it without holding it in memory
+    async fn stream(&self, key: &str) -> anyhow::Result<Option<StoredObject>>;
+
+    /// length of a file in bytes
+    async fn size(&self, key: &str) -> anyhow::Result<Option<u64>>;
+
+    /// read a part of a file a chunk at a time, the range must be inside the file
+    async fn stream_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> anyhow::Result<Option<ByteStream>>;
+
+    /// Keys starting with a prefix, such as `cache/3/`. Only the keys in the
+    /// "folder" of the prefix are listed, not the ones in its sub folders.
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>>;
+}
+
+/// open the storage chosen by the configuration
+pub fn open(config: &StorageConfig) -> anyhow::Result<SharedStorage> {
+    Ok(match config {
+        StorageConfig::Local { root } => Arc::new(LocalStorage::new(root.clone())),
+        StorageConfig::S3(config) => Arc::new(S3Storage::new(config)?),
+    })
+}
+
+/// Files under a folder of this machine, a key is a path relative to it
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        LocalStorage { root }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> anyhow::Result<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::task::spawn_blocking(move || write_atomically(&path, &bytes))
+            .await?
+            .with_context(|| format!("can't write {key}"))
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(key)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("can't read {key}")),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("can't remove {key}")),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(tokio::fs::try_exists(self.path(key)).await?)
+    }
+
+    async fn stream(&self, key: &str) -> anyhow::Result<Option<StoredObject>> {
+        let file = match tokio::fs::File::open(self.path(key)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("can't open {key}")),
+        };
+        let size = file.metadata().await?.len();
+        Ok(Some(StoredObject {
+            size,
+            body: ReaderStream::new(file).boxed(),
+        }))
+    }
+
+    async fn size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        match tokio::fs::metadata(self.path(key)).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("can't read {key}")),
+        }
+    }
+
+    async fn stream_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> anyhow::Result<Option<ByteStream>> {
+        let mut file = match tokio::fs::File::open(self.path(key)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("can't open {key}")),
+        };
+        file.seek(SeekFrom::Start(range.start)).await?;
+        let part = file.take(range.end - range.start);
+        Ok(Some(ReaderStream::new(part).boxed()))
+    }
+
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let (folder, name_prefix) = prefix.rsplit_once('/').unwrap_or(("", prefix));
+        let mut entries = match tokio::fs::read_dir(self.root.join(folder)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut keys = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(name_prefix) && entry.file_type().await?.is_file() {
+                keys.push(match folder {
+                    "" => name,
+                    folder => format!("{folder}/{name}"),
+                });
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Objects in a bucket of S3 or of a compatible server (MinIO, Garage...),
+/// the key is the object name
+pub struct S3Storage {
+    bucket: Box<Bucket>,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> anyhow::Result<Self> {
+        let region = match &config.endpoint {
+            Some(endpoint) => Region::Custom {
+                region: config.region.clone(),
+                endpoint: endpoint.clone(),
+            },
+            None => config.region.parse()?,
+        };
+        // without keys, the usual AWS variables and profile are used
+        let credentials = Credentials::new(
+            config.access_key.as_deref(),
+            config.secret_key.as_deref(),
+            None,
+            None,
+            None,
+        )?;
+
+        let mut bucket = Bucket::new(&config.bucket, region, credentials)?;
+        if config.path_style {
+            bucket = bucket.with_path_style();
+        }
+        Ok(S3Storage { bucket })
+    }
+}
+
+/// turn an unexpected HTTP status of the S3 server into an error
+fn check_status(status: u16, action: &str, key: &str) -> anyhow::Result<()> {
+    if !(200..300).contains(&status) {
+        anyhow::bail!("can't {action} {key}: the S3 server answered {status}");
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> anyhow::Result<()> {
+        // an object is replaced in one go by S3
+        let response = self.bucket.put_object(key, &bytes).await?;
+        check_status(response.status_code(), "write", key)
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let response = self.bucket.get_object(key).await?;
+        if response.status_code() == 404 {
+            return Ok(None);
+        }
+        check_status(response.status_code(), "read", key)?;
+        Ok(Some(response.to_vec()))
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let response = self.bucket.delete_object(key).await?;
+        if response.status_code() == 404 {
+            return Ok(());
+        }
+        check_status(response.status_code(), "remove", key)
+    }
+
+    async fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.bucket.object_exists(key).await?)
+    }
+
+    async fn stream(&self, key: &str) -> anyhow::Result<Option<StoredObject>> {
+        // the length is not part of the streamed response
+        let (head, status) = self.bucket.head_object(key).await?;
+        if status == 404 {
+            return Ok(None);
+        }
+        check_status(status, "read", key)?;
+
+        let response = self.bucket.get_object_stream(key).await?;
+        if response.status_code == 404 {
+            return Ok(None);
+        }
+        check_status(response.status_code, "read", key)?;
+        Ok(Some(StoredObject {
+            size: head.content_length.unwrap_or(0).max(0) as u64,
+            body: response.bytes.map_err(std::io::Error::other).boxed(),
+        }))
+    }
+
+    async fn size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        let (head, status) = self.bucket.head_object(key).await?;
+        if status == 404 {
+            return Ok(None);
+        }
+        check_status(status, "read", key)?;
+        Ok(Some(head.content_length.unwrap_or(0).max(0) as u64))
+    }
+
+    async fn stream_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> anyhow::Result<Option<ByteStream>> {
+        if range.is_empty() {
+            return Ok(Some(futures::stream::empty().boxed()));
+        }
+        // Bucket only streams whole objects, the ranged request is made by hand.
+        // The end of an HTTP range is included.
+        let command = Command::GetObjectRange {
+            start: range.start,
+            end: Some(range.end - 1),
+        };
+        let response = ReqwestRequest::new(&self.bucket, key, command)
+            .await?
+            .response_data_to_stream()
+            .await?;
+        if response.status_code == 404 {
+            return Ok(None);
+        }
+        check_status(response.status_code, "read", key)?;
+        Ok(Some(response.bytes.map_err(std::io::Error::other).boxed()))
+    }
+
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let pages = self
+            .bucket
+            .list(prefix.to_string(), Some("/".to_string()))
+            .await?;
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| object.key)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn collect(stream: ByteStream) -> Vec<u8> {
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    /// what every backend must do, with keys under `prefix`
+    async fn check_storage(storage: &dyn Storage, prefix: &str) {
+        let key = format!("{prefix}/3_small.jpg");
+        storage.put(&key, b"0123456789".to_vec()).await.unwrap();
+        assert!(storage.exists(&key).await.unwrap());
+        assert_eq!(storage.get(&key).await.unwrap().unwrap(), b"0123456789");
+        assert_eq!(storage.size(&key).await.unwrap(), Some(10));
+
+        let object = storage.stream(&key).await.unwrap().unwrap();
+        assert_eq!(object.size, 10);
+        assert_eq!(collect(object.body).await, b"0123456789");
+        let part = storage.stream_range(&key, 2..5).await.unwrap().unwrap();
+        assert_eq!(collect(part).await, b"234");
+        let last = storage.stream_range(&key, 9..10).await.unwrap().unwrap();
+        assert_eq!(collect(last).await, b"9");
+
+        // replaced as a whole
+        storage.put(&key, b"new".to_vec()).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap().unwrap(), b"new");
+
+        // only the folder of the prefix is listed
+        for other in ["3_large.jpg", "30_small.jpg", "3/display.jpg"] {
+            storage
+                .put(&format!("{prefix}/{other}"), b"x".to_vec())
+                .await
+                .unwrap();
+        }
+        let mut listed = storage.list(&format!("{prefix}/3_")).await.unwrap();
+        listed.sort();
+        assert_eq!(
+            listed,
+            [
+                format!("{prefix}/3_large.jpg"),
+                format!("{prefix}/3_small.jpg")
+            ]
+        );
+        assert_eq!(
+            storage.list(&format!("{prefix}/3/")).await.unwrap(),
+            [format!("{prefix}/3/display.jpg")]
+        );
+        assert!(storage
+            .list(&format!("{prefix}/nothing/"))
+            .await
+            .unwrap()
+            .is_empty());
+
+        for key in storage.list(&format!("{prefix}/")).await.unwrap() {
+            storage.delete(&key).await.unwrap();
+        }
+        storage
+            .delete(&format!("{prefix}/3/display.jpg"))
+            .await
+            .unwrap();
+        assert!(!storage.exists(&key).await.unwrap());
+
+        // a missing key is not an error
+        assert!(storage.get(&key).await.unwrap().is_none());
+        assert!(storage.size(&key).await.unwrap().is_none());
+        assert!(storage.stream(&key).await.unwrap().is_none());
+        assert!(storage.stream_range(&key, 0..1).await.unwrap().is_none());
+        storage.delete(&key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_storage() {
+        let root = tempfile::tempdir().unwrap();
+        check_storage(&LocalStorage::new(root.path().to_path_buf()), "test").await;
+    }
+
+    /// Needs an S3 compatible server, such as a MinIO started with
+    /// `docker run -p 9000:9000 minio/minio server /data` and a bucket:
+    /// `S3_ENDPOINT=http://localhost:9000 S3_BUCKET=thumbnails S3_ACCESS_KEY=minioadmin
+    /// S3_SECRET_KEY=minioadmin cargo test s3_storage -- --ignored`
+    #[tokio::test]
+    #[ignore = "needs an S3 server, given by S3_ENDPOINT"]
+    async fn s3_storage() {
+        let variable = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let config = S3Config {
+            bucket: variable("S3_BUCKET").unwrap_or_else(|| "thumbnails".to_string()),
+            region: variable("S3_REGION").unwrap_or_else(|| "us-east-1".to_string()),
+            endpoint: Some(variable("S3_ENDPOINT").expect("S3_ENDPOINT is required")),
+            access_key: variable("S3_ACCESS_KEY"),
+            secret_key: variable("S3_SECRET_KEY"),
+            path_style: true,
+        };
+        // a folder of its own, the bucket may be shared
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        check_storage(&S3Storage::new(&config).unwrap(), &format!("test-{nanos}")).await;
+    }
+}
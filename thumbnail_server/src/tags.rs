@@ -0,0 +1,199 @@
+use axum::{Extension, Json};
+use serde::Serialize;
+use sqlx::{prelude::FromRow, Sqlite, SqlitePool, Transaction};
+
+use crate::error::AppError;
+
+/// Split a free-form tag string ("cat, garden  sunset") into tags.
+/// Tags are separated by commas or whitespace, compared in lowercase,
+/// and each tag is kept once.
+pub fn parse_tags(text: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in text.split(|c: char| c == ',' || c.is_whitespace()) {
+        let tag = tag.trim().to_lowercase();
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+/// The migration that split the old tag strings lowercased them with SQLite,
+/// which only knows ASCII: `ÉTÉ` stayed as it was, and a non-breaking space
+/// didn't separate two tags. Give every tag the form `parse_tags` gives, merging
+/// it with the tag that already has that form.
+pub async fn normalize_existing_tags(pool: &SqlitePool) -> anyhow::Result<()> {
+    let tags: Vec<(i64, String)> = sqlx::query_as("SELECT id, name FROM tags")
+        .fetch_all(pool)
+        .await?;
+
+    for (id, name) in tags {
+        let normalized = parse_tags(&name);
+        if normalized == [name.as_str()] {
+            continue;
+        }
+
+        let mut tx = pool.begin().await?;
+        let images: Vec<i64> =
+            sqlx::query_scalar("SELECT image_id FROM image_tags WHERE tag_id = ?")
+                .bind(id)
+                .fetch_all(&mut *tx)
+                .await?;
+        for image_id in images {
+            add_image_tags(&mut tx, image_id, &normalized).await?;
+        }
+        // the links first, their trigger updates the search index
+        sqlx::query("DELETE FROM image_tags WHERE tag_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM tags WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
+
+/// attach tags to an image, creating the tags that don't exist yet
+pub async fn add_image_tags(
+    tx: &mut Transaction<'_, Sqlite>,
+    image_id: i64,
+    tags: &[String],
+) -> anyhow::Result<()> {
+    for tag in tags {
+        sqlx::query("INSERT OR IGNORE INTO tags (name) VALUES (?)")
+            .bind(tag)
+            .execute(&mut **tx)
+            .await?;
+        sqlx::query(
+            "INSERT OR IGNORE INTO image_tags (image_id, tag_id)
+             SELECT ?, id FROM tags WHERE name = ?",
+        )
+        .bind(image_id)
+        .bind(tag)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// detach tags from an image, the tags themselves stay
+pub async fn remove_image_tags(
+    tx: &mut Transaction<'_, Sqlite>,
+    image_id: i64,
+    tags: &[String],
+) -> anyhow::Result<()> {
+    for tag in tags {
+        sqlx::query(
+            "DELETE FROM image_tags
+             WHERE image_id = ? AND tag_id = (SELECT id FROM tags WHERE name = ?)",
+        )
+        .bind(image_id)
+        .bind(tag)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// A tag and the number of images using it
+#[derive(Serialize, FromRow, Debug)]
+pub struct TagCount {
+    name: String,
+    count: i64,
+}
+
+/// list every tag with the number of images using it, most used first
+pub async fn list_tags(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+) -> Result<Json<Vec<TagCount>>, AppError> {
+    let tags = sqlx::query_as::<_, TagCount>(
+        "SELECT tags.name, COUNT(image_tags.image_id) AS count
+         FROM tags
+         JOIN image_tags ON image_tags.tag_id = tags.id
+         GROUP BY tags.id
+         ORDER BY count DESC, tags.name",
+    )
+    .fetch_all(&pool)
+    .await?;
+    Ok(Json(tags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_pool;
+
+    #[test]
+    fn tags_are_split_on_commas_and_whitespace() {
+        assert_eq!(
+            parse_tags("cat, garden  sunset\tsky\nsea,,beach ,"),
+            ["cat", "garden", "sunset", "sky", "sea", "beach"]
+        );
+        // any Unicode whitespace
+        assert_eq!(
+            parse_tags("rouge\u{a0}vert\u{3000}bleu"),
+            ["rouge", "vert", "bleu"]
+        );
+        assert!(parse_tags(" , \t,").is_empty());
+        assert!(parse_tags("").is_empty());
+    }
+
+    #[test]
+    fn tags_are_lowercased_and_kept_once() {
+        assert_eq!(parse_tags("Cat cat CAT dog Cat"), ["cat", "dog"]);
+        assert_eq!(parse_tags("ÉTÉ été Straße"), ["été", "straße"]);
+        // in the order they first appear
+        assert_eq!(parse_tags("b a b c a"), ["b", "a", "c"]);
+        // the other characters are part of the tag
+        assert_eq!(parse_tags("new-york c++ #sun"), ["new-york", "c++", "#sun"]);
+    }
+
+    /// the tags of an image, sorted
+    async fn image_tags(pool: &SqlitePool, image_id: i64) -> Vec<String> {
+        sqlx::query_scalar(
+            "SELECT tags.name FROM image_tags JOIN tags ON tags.id = image_tags.tag_id
+             WHERE image_tags.image_id = ? ORDER BY tags.name",
+        )
+        .bind(image_id)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn existing_tags_are_normalized() {
+        let pool = test_pool().await;
+        // what the migration left: SQLite lowercased the ASCII letters only
+        sqlx::raw_sql(
+            "INSERT INTO images (id) VALUES (1), (2);
+             INSERT INTO tags (id, name) VALUES
+                 (1, 'été'), (2, 'ÉtÉ'), (3, 'straße'), (4, 'rouge\u{a0}vert'), (5, 'ÅRHUS');
+             INSERT INTO image_tags (image_id, tag_id) VALUES
+                 (1, 1), (1, 2), (1, 4),
+                 (2, 2), (2, 3), (2, 5);",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        normalize_existing_tags(&pool).await.unwrap();
+
+        assert_eq!(image_tags(&pool, 1).await, ["rouge", "vert", "été"]);
+        assert_eq!(image_tags(&pool, 2).await, ["straße", "århus", "été"]);
+        let names: Vec<String> = sqlx::query_scalar("SELECT name FROM tags ORDER BY name")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(names, ["rouge", "straße", "vert", "århus", "été"]);
+        // the search index follows
+        let found: Vec<i64> =
+            sqlx::query_scalar("SELECT rowid FROM images_fts WHERE images_fts MATCH 'tags:vert'")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(found, [1]);
+    }
+}
@@ -0,0 +1,20 @@
+//! Helpers shared by the tests
+
+use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+
+/// An empty database in memory. It has a single connection: every connection
+/// to `sqlite::memory:` opens a database of its own.
+pub async fn empty_pool() -> SqlitePool {
+    SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap()
+}
+
+/// a database in memory with every migration applied
+pub async fn test_pool() -> SqlitePool {
+    let pool = empty_pool().await;
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    pool
+}
@@ -0,0 +1,638 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{multipart::Field, Multipart},
+    response::Html,
+    Extension, Json,
+};
+use image::ImageFormat;
+use serde::Serialize;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use tokio::{
+    sync::Semaphore,
+    task::{spawn_blocking, JoinHandle},
+};
+
+use crate::{
+    config::Config,
+    error::{escape_html, AppError, ErrorBody, HtmlError},
+    files::{blob_key, content_hash, content_in_use, lock_content, private_blob_key},
+    format_name,
+    imaging::{decoded_size, DecodeBudget},
+    jobs::{self, JobKind, JobQueue},
+    metadata::{read_metadata, save_metadata},
+    privacy::{public_metadata, strip_metadata},
+    similar::{perceptual_hash, SimilarIndex},
+    storage::{SharedStorage, Storage},
+    tags::{self, parse_tags},
+    validation::validate_image,
+};
+
+/// largest text field (tags, title...) of the upload form, in bytes
+const MAX_TEXT_BYTES: usize = 64 * 1024;
+
+/// The upload form. A single image gives a page waiting for its thumbnail,
+/// several images the list of what became of each of them.
+pub async fn uploader(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(queue): Extension<JobQueue>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(index): Extension<SimilarIndex>,
+    Extension(budget): Extension<DecodeBudget>,
+    multipart: Multipart,
+) -> Result<Html<String>, HtmlError> {
+    let context = UploadContext::new(pool, config, storage, queue, index, budget);
+    let mut uploads = receive_uploads(&context, multipart).await?;
+
+    if uploads.len() > 1 {
+        let mut results = String::new();
+        for upload in uploads {
+            let file = escape_html(upload.file.as_deref().unwrap_or("image"));
+            let message = match &upload.result {
+                Ok(stored) => upload_message(stored),
+                Err(e) => escape_html(&e.message()),
+            };
+            results.push_str(&format!("<li>{file}: {message}</li>"));
+        }
+        let path = std::path::Path::new("src/uploaded.html");
+        let content = tokio::fs::read_to_string(path).await?;
+        return Ok(Html(content.replace("{results}", &results)));
+    }
+
+    let stored = uploads.remove(0).result?;
+    // redirect user after upload image, once the thumbnail is ready
+    let message = upload_message(&stored);
+    let job_id = stored
+        .thumbnail_job_id
+        .map_or("null".to_string(), |id| id.to_string());
+    let path = std::path::Path::new("src/redirect.html");
+    let content = tokio::fs::read_to_string(path).await?;
+    let content = content
+        .replace("{message}", &message)
+        .replace("{job_id}", &job_id)
+        // the user stays on the page to read the warning
+        .replace("{stay}", &(!stored.similar.is_empty()).to_string());
+    Ok(Html(content))
+}
+
+/// same as the upload form for scripts: the result of every image,
+/// in the order they were sent
+pub async fn api_uploader(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(queue): Extension<JobQueue>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(index): Extension<SimilarIndex>,
+    Extension(budget): Extension<DecodeBudget>,
+    multipart: Multipart,
+) -> Result<Json<Vec<UploadResult>>, AppError> {
+    let context = UploadContext::new(pool, config, storage, queue, index, budget);
+    let uploads = receive_uploads(&context, multipart).await?;
+    Ok(Json(uploads.into_iter().map(UploadResult::from).collect()))
+}
+
+/// what the user is told about a stored image, in HTML
+fn upload_message(stored: &StoredImage) -> String {
+    let mut message = if stored.duplicate {
+        format!(
+            "Image {} was already uploaded, the tags were added to it.",
+            stored.id
+        )
+    } else {
+        format!("Image {} Uploaded!", stored.id)
+    };
+    if !stored.similar.is_empty() {
+        let links: Vec<String> = stored
+            .similar
+            .iter()
+            .map(|id| format!("<a href=\"/image/{id}\">{id}</a>"))
+            .collect();
+        message.push_str(&format!(
+            " It looks like images already uploaded: {}.",
+            links.join(", ")
+        ));
+    }
+    message
+}
+
+/// What the images of an upload request share
+#[derive(Clone)]
+pub struct UploadContext {
+    pool: SqlitePool,
+    config: Arc<Config>,
+    storage: SharedStorage,
+    queue: JobQueue,
+    index: SimilarIndex,
+    budget: DecodeBudget,
+    /// bounds the number of images stored at the same time
+    limit: Arc<Semaphore>,
+}
+
+impl UploadContext {
+    pub fn new(
+        pool: SqlitePool,
+        config: Arc<Config>,
+        storage: SharedStorage,
+        queue: JobQueue,
+        index: SimilarIndex,
+        budget: DecodeBudget,
+    ) -> Self {
+        let limit = Arc::new(Semaphore::new(config.upload_concurrency));
+        UploadContext {
+            pool,
+            config,
+            storage,
+            queue,
+            index,
+            budget,
+            limit,
+        }
+    }
+}
+
+/// An image of the upload form (or of an imported archive), read but not stored yet
+pub struct UploadedFile {
+    /// name of the file on the computer of the uploader
+    pub name: Option<String>,
+    /// the content, or why it was refused while it was read
+    pub bytes: Result<Vec<u8>, AppError>,
+    /// tags given to this image alone, added to the shared ones
+    pub tags: Option<String>,
+    /// title and description of this image alone, replacing the shared ones
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+/// What became of an image of the upload form
+pub struct FileUpload {
+    /// name of the file on the computer of the uploader
+    pub file: Option<String>,
+    pub result: Result<StoredImage, AppError>,
+}
+
+/// JSON result of an image of `/api/upload`: what `store_image` created,
+/// or `{"error": ..., "message": ...}`
+#[derive(Serialize, Debug)]
+pub struct UploadResult {
+    file: Option<String>,
+    #[serde(flatten)]
+    outcome: UploadOutcome,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum UploadOutcome {
+    Stored(StoredImage),
+    Failed(ErrorBody),
+}
+
+impl From<FileUpload> for UploadResult {
+    fn from(upload: FileUpload) -> Self {
+        let outcome = match upload.result {
+            Ok(stored) => UploadOutcome::Stored(stored),
+            Err(e) => UploadOutcome::Failed(e.body()),
+        };
+        UploadResult {
+            file: upload.file,
+            outcome,
+        }
+    }
+}
+
+/// an image being stored in the background, or refused before
+pub type RunningUpload = (
+    Option<String>,
+    Result<JoinHandle<Result<StoredImage, AppError>>, AppError>,
+);
+
+/// Read an upload form and store its images. The fields are:
+///
+/// - `tags`, `title` and `description`, shared by every image and sent before them
+/// - one `image` field per image
+/// - optionally, right after an image, an `image_tags` field with its own tags
+///
+/// Each image is stored as soon as it is read, `upload_concurrency` at a time,
+/// so only a few of them are held in memory. An image that can't be stored
+/// doesn't stop the others, its error is part of the results. An invalid form
+/// is an error of the whole request, unless images are already being stored:
+/// the results then end with the error, given to the image read last (which
+/// is not stored) when there is one.
+async fn receive_uploads(
+    context: &UploadContext,
+    multipart: Multipart,
+) -> Result<Vec<FileUpload>, AppError> {
+    let mut form = UploadForm::default();
+    if let Err(error) = form.read(context, multipart).await {
+        if form.running.is_empty() {
+            return Err(error);
+        }
+        let mut uploads = finish_uploads(form.running).await;
+        uploads.push(FileUpload {
+            file: form.pending.and_then(|file| file.name),
+            result: Err(error),
+        });
+        return Ok(uploads);
+    }
+
+    if form.running.is_empty() {
+        return Err(AppError::BadRequest(
+            "Missing field: at least one image is required".to_string(),
+        ));
+    }
+    Ok(finish_uploads(form.running).await)
+}
+
+/// An upload form being read
+#[derive(Default)]
+struct UploadForm {
+    details: ImageDetails,
+    files: usize,
+    /// an image waits for the field after it, in case it is its `image_tags`
+    pending: Option<UploadedFile>,
+    running: Vec<RunningUpload>,
+}
+
+impl UploadForm {
+    /// read the fields, starting to store each image once the field after it is read
+    async fn read(
+        &mut self,
+        context: &UploadContext,
+        mut multipart: Multipart,
+    ) -> Result<(), AppError> {
+        let config = &context.config;
+        while let Some(mut field) = multipart
+            .next_field()
+            .await
+            .map_err(AppError::from_multipart)?
+        {
+            let name = field.name().unwrap_or_default().to_string();
+
+            match name.as_str() {
+                "tags" | "title" | "description" => {
+                    if self.files > 0 {
+                        return Err(AppError::BadRequest(format!(
+                            "{name} must be sent before the images"
+                        )));
+                    }
+                    let text = read_text(&name, &mut field).await?;
+                    match name.as_str() {
+                        "tags" => self.details.tags = text,
+                        "title" => self.details.title = text,
+                        _ => self.details.description = text,
+                    }
+                }
+                "image" => {
+                    self.start_pending(context).await?;
+                    self.files += 1;
+                    let file_name = field.file_name().map(str::to_string);
+                    let bytes = if self.files > config.upload_max_files {
+                        read_field(&mut field, 0).await?;
+                        Err(AppError::PayloadTooLarge(format!(
+                            "At most {} images can be uploaded at once",
+                            config.upload_max_files
+                        )))
+                    } else {
+                        read_field(&mut field, config.upload_max_bytes)
+                            .await?
+                            .ok_or_else(|| {
+                                AppError::PayloadTooLarge(format!(
+                                    "The image is larger than {} bytes",
+                                    config.upload_max_bytes
+                                ))
+                            })
+                    };
+                    self.pending = Some(UploadedFile {
+                        name: file_name,
+                        bytes,
+                        tags: None,
+                        title: None,
+                        description: None,
+                    });
+                }
+                "image_tags" => {
+                    let text = read_text(&name, &mut field).await?;
+                    match &mut self.pending {
+                        Some(file) if file.tags.is_none() => file.tags = Some(text),
+                        _ => {
+                            return Err(AppError::BadRequest(
+                                "image_tags must be sent right after its image".to_string(),
+                            ))
+                        }
+                    }
+                }
+                _ => return Err(AppError::BadRequest(format!("Unknown field: {name}"))),
+            }
+        }
+        self.start_pending(context).await
+    }
+
+    /// start storing the image read last
+    async fn start_pending(&mut self, context: &UploadContext) -> Result<(), AppError> {
+        if let Some(file) = self.pending.take() {
+            self.running
+                .push(start_upload(context, &self.details, file).await?);
+        }
+        Ok(())
+    }
+}
+
+/// wait for every image to be stored, in the order they were started
+pub async fn finish_uploads(running: Vec<RunningUpload>) -> Vec<FileUpload> {
+    let mut uploads = Vec::with_capacity(running.len());
+    for (file, task) in running {
+        let result = match task {
+            Ok(task) => task.await.unwrap_or_else(|e| Err(e.into())),
+            Err(e) => Err(e),
+        };
+        uploads.push(FileUpload { file, result });
+    }
+    uploads
+}
+
+/// Store an image in the background, once fewer than `upload_concurrency`
+/// images are being stored. Waiting here stops the form from being read further.
+pub async fn start_upload(
+    context: &UploadContext,
+    shared: &ImageDetails,
+    file: UploadedFile,
+) -> Result<RunningUpload, AppError> {
+    let bytes = match file.bytes {
+        Ok(bytes) => bytes,
+        Err(e) => return Ok((file.name, Err(e))),
+    };
+    let details = ImageDetails {
+        tags: match &file.tags {
+            Some(tags) => format!("{}, {tags}", shared.tags),
+            None => shared.tags.clone(),
+        },
+        title: file.title.unwrap_or_else(|| shared.title.clone()),
+        description: file
+            .description
+            .unwrap_or_else(|| shared.description.clone()),
+    };
+
+    let permit = context.limit.clone().acquire_owned().await?;
+    let context = context.clone();
+    let task = tokio::spawn(async move {
+        let _permit = permit;
+        store_image(&context, &details, bytes).await
+    });
+    Ok((file.name, Ok(task)))
+}
+
+/// Read a field of the multipart form, up to `max` bytes. A larger field
+/// is skipped and gives `None`, the next fields can still be read.
+pub async fn read_field(field: &mut Field<'_>, max: usize) -> Result<Option<Vec<u8>>, AppError> {
+    let mut data = Vec::new();
+    let mut too_large = false;
+    while let Some(chunk) = field.chunk().await.map_err(AppError::from_multipart)? {
+        if too_large {
+            continue;
+        }
+        if data.len() + chunk.len() > max {
+            too_large = true;
+            data = Vec::new();
+            continue;
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok((!too_large).then_some(data))
+}
+
+/// read a text field of the multipart form
+pub async fn read_text(name: &str, field: &mut Field<'_>) -> Result<String, AppError> {
+    let Some(data) = read_field(field, MAX_TEXT_BYTES).await? else {
+        return Err(AppError::PayloadTooLarge(format!("{name} is too long")));
+    };
+    String::from_utf8(data)
+        .map(|text| text.trim().to_string())
+        .map_err(|_| AppError::BadRequest(format!("{name} must be valid UTF-8")))
+}
+
+/// What the uploader tells about an image
+#[derive(Debug, Clone, Default)]
+pub struct ImageDetails {
+    /// free-form tags, see `tags::parse_tags`
+    pub tags: String,
+    pub title: String,
+    pub description: String,
+}
+
+/// What `store_image` created
+#[derive(Serialize, Debug, Clone)]
+pub struct StoredImage {
+    pub id: i64,
+    /// the job generating the thumbnails, poll `/jobs/:id` to know when they are ready.
+    /// Missing for known content whose thumbnails were made before the job queue.
+    pub thumbnail_job_id: Option<i64>,
+    /// the same content was already stored, `id` is the existing image
+    /// and the tags were added to it
+    pub duplicate: bool,
+    /// images that look the same (resized, re-encoded copies...), closest first
+    pub similar: Vec<i64>,
+}
+
+/// Store an upload as a single unit of work: either the row, the original and
+/// the job generating its thumbnails exist, or none of them do.
+///
+/// 1. validate the upload, read its metadata and hash it
+/// 2. store the original (and its stripped copy in privacy mode) under the hash
+/// 3. insert the rows, queue the thumbnail job and commit, in a short transaction
+///
+/// The files are written before the transaction starts: the database stays
+/// free for the other writers meanwhile. They are named after their content, a
+/// leftover of a crash is replaced by the same bytes. When the same content was
+/// already uploaded nothing new is stored, the tags are added to the existing image.
+///
+/// On any error the transaction is rolled back (by dropping it) and the
+/// files are removed, unless another image holds the same content.
+pub async fn store_image(
+    context: &UploadContext,
+    details: &ImageDetails,
+    bytes: Vec<u8>,
+) -> Result<StoredImage, AppError> {
+    let UploadContext {
+        pool,
+        storage,
+        queue,
+        index,
+        ..
+    } = context;
+    let storage = storage.as_ref();
+    let config = context.config.clone();
+    let max_distance = config.similar_max_distance;
+    // a larger image fails to decode anyway
+    let max_alloc = config.decoder_limits().max_alloc.unwrap_or(u64::MAX);
+    let decoding = context
+        .budget
+        .reserve(decoded_size(&bytes).min(max_alloc))
+        .await;
+    // the CPU heavy part happens before we hold any lock on the database
+    let strip = config.strip_metadata;
+    let (bytes, public, format, metadata, hash, look) = spawn_blocking(move || {
+        let _decoding = decoding;
+        // don't store something we will never be able to turn into a thumbnail
+        let (format, image) = validate_image(&bytes, &config)?;
+        let metadata = read_metadata(&bytes)?;
+        let hash = content_hash(&bytes);
+        let look = perceptual_hash(&image);
+        // stripping may decode the image again
+        drop(image);
+
+        // in privacy mode the served copy is a stripped one
+        if !strip {
+            return Ok::<_, AppError>((bytes, None, format, metadata, hash, look));
+        }
+        let public = strip_metadata(&bytes, format, metadata.orientation)?;
+        let metadata = public_metadata(&public)?;
+        Ok((bytes, Some(public), format, metadata, hash, look))
+    })
+    .await??;
+
+    // a removal of the same content waits until the row is there
+    let _content = lock_content(&hash).await;
+    let mut written = Vec::new();
+    let result = async {
+        let size = public.as_ref().map_or(bytes.len(), Vec::len);
+        let tags = parse_tags(&details.tags);
+
+        // The files of known content are left as they are, they may have been
+        // stripped or not. They can't be removed before the lock is released,
+        // even when their image is deleted meanwhile.
+        let key = blob_key(&hash, format);
+        match public {
+            _ if content_in_use(pool, &hash).await? => {}
+            Some(public) => {
+                let private_key = private_blob_key(&hash, format);
+                written.push(private_key.clone());
+                storage.put(&private_key, bytes).await?;
+                written.push(key.clone());
+                storage.put(&key, public).await?;
+            }
+            None => {
+                written.push(key.clone());
+                storage.put(&key, bytes).await?;
+            }
+        }
+
+        let mut tx = pool.begin().await?;
+        let stored = StoredFile {
+            hash: &hash,
+            perceptual_hash: look,
+            format,
+            size,
+            metadata_stripped: strip,
+        };
+        let inserted = insert_image_into_database(&mut tx, details, &stored).await?;
+        let Some(id) = inserted else {
+            let id = add_to_existing_image(&mut tx, &hash, &tags).await?;
+            tx.commit().await?;
+            return Ok(StoredImage {
+                id,
+                thumbnail_job_id: latest_thumbnail_job(pool, id).await?,
+                duplicate: true,
+                similar: Vec::new(),
+            });
+        };
+        tags::add_image_tags(&mut tx, id, &tags).await?;
+        save_metadata(&mut *tx, id, &metadata).await?;
+        let thumbnail_job_id = jobs::enqueue(&mut tx, JobKind::Thumbnail, id).await?;
+
+        tx.commit().await?;
+        let similar = index.find(look, max_distance, None);
+        index.insert(id, look);
+        Ok::<_, AppError>(StoredImage {
+            id,
+            thumbnail_job_id: Some(thumbnail_job_id),
+            duplicate: false,
+            similar: similar.into_iter().map(|(id, _)| id).collect(),
+        })
+    }
+    .await;
+
+    match &result {
+        Ok(_) => queue.wake(),
+        Err(_) => match content_in_use(pool, &hash).await {
+            Ok(false) => remove_files(storage, &written).await,
+            Ok(true) => {}
+            Err(e) => {
+                eprintln!("can't tell whether the files of {hash} are in use, they stay: {e:#}")
+            }
+        },
+    }
+    result
+}
+
+/// What is known about the file of an upload before it is inserted
+struct StoredFile<'a> {
+    /// SHA-256 of the upload
+    hash: &'a str,
+    perceptual_hash: u64,
+    format: ImageFormat,
+    /// size of the served file
+    size: usize,
+    metadata_stripped: bool,
+}
+
+/// Insert the row of a new image. Gives `None` when an image with the same
+/// content already exists, the unique index on the hash settles concurrent uploads.
+async fn insert_image_into_database(
+    tx: &mut Transaction<'_, Sqlite>,
+    details: &ImageDetails,
+    file: &StoredFile<'_>,
+) -> anyhow::Result<Option<i64>> {
+    let id = sqlx::query_scalar(
+        "INSERT INTO images (title, description, content_hash, perceptual_hash,
+                             format, size, metadata_stripped, created_at, file_modified_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, unixepoch(), unixepoch())
+         ON CONFLICT (content_hash) DO NOTHING
+         RETURNING id",
+    )
+    .bind(&details.title)
+    .bind(&details.description)
+    .bind(file.hash)
+    // SQLite has no unsigned 64 bits type
+    .bind(file.perceptual_hash as i64)
+    .bind(format_name(file.format))
+    .bind(file.size as i64)
+    .bind(file.metadata_stripped)
+    .fetch_optional(&mut **tx)
+    .await?;
+    Ok(id)
+}
+
+/// merge the tags of a new upload of known content into the existing image
+async fn add_to_existing_image(
+    tx: &mut Transaction<'_, Sqlite>,
+    hash: &str,
+    tags: &[String],
+) -> anyhow::Result<i64> {
+    let id = sqlx::query_scalar("SELECT id FROM images WHERE content_hash = ?")
+        .bind(hash)
+        .fetch_one(&mut **tx)
+        .await?;
+    tags::add_image_tags(tx, id, tags).await?;
+    Ok(id)
+}
+
+/// the last job generating the thumbnails of an image
+async fn latest_thumbnail_job(pool: &sqlx::SqlitePool, id: i64) -> anyhow::Result<Option<i64>> {
+    let job_id = sqlx::query_scalar(
+        "SELECT id FROM jobs WHERE image_id = ? AND kind = ? ORDER BY id DESC LIMIT 1",
+    )
+    .bind(id)
+    .bind(JobKind::Thumbnail)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job_id)
+}
+
+/// best effort removal of the files of a failed upload
+async fn remove_files(storage: &dyn Storage, keys: &[String]) {
+    for key in keys {
+        if let Err(e) = storage.delete(key).await {
+            eprintln!("failed to clean up {key}: {e:#}");
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageFormat, ImageReader};
+
+use crate::{config::Config, error::AppError, imaging::load_image_within};
+
+/// Check that an upload really is an image we can handle, before anything is written.
+///
+/// - the magic bytes must be a known and allowed format (415 otherwise)
+/// - the header must decode and the dimensions must be within the limits (422 otherwise)
+/// - the whole image must decode, which catches truncated uploads (422 otherwise)
+///
+/// Returns the detected format and the decoded image, the right way up.
+/// Decoding is CPU heavy, call it from `spawn_blocking`.
+pub fn validate_image(
+    bytes: &[u8],
+    config: &Config,
+) -> Result<(ImageFormat, DynamicImage), AppError> {
+    if bytes.is_empty() {
+        return Err(AppError::Unprocessable(
+            "The uploaded file is empty".to_string(),
+        ));
+    }
+
+    let format = image::guess_format(bytes).map_err(|_| {
+        AppError::UnsupportedMedia("The uploaded file is not a known image format".to_string())
+    })?;
+    if !config.upload_allowed_formats.contains(&format) {
+        return Err(AppError::UnsupportedMedia(format!(
+            "{} images are not accepted",
+            format.extensions_str()[0]
+        )));
+    }
+
+    // only the header is read here, so a small file claiming a huge size
+    // is rejected before we allocate anything
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(config.decoder_limits());
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|e| AppError::Unprocessable(format!("The image header can't be read: {e}")))?;
+    if width > config.upload_max_width || height > config.upload_max_height {
+        return Err(AppError::Unprocessable(format!(
+            "The image is {width}x{height}, the maximum is {}x{}",
+            config.upload_max_width, config.upload_max_height
+        )));
+    }
+
+    let image = load_image_within(bytes, config.decoder_limits())
+        .map_err(|e| AppError::Unprocessable(format!("The image can't be decoded: {e:#}")))?;
+
+    Ok((format, image))
+}